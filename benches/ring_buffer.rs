@@ -0,0 +1,35 @@
+//! Compares a one-second delay line built on `RingBuffer` against the `Vec` with `remove(0)` it replaced.
+//! Run with `cargo bench --bench ring_buffer`.
+
+use gran::effects::RingBuffer;
+use std::{hint::black_box, time::Instant};
+
+const SAMPLE_RATE: usize = 48000;
+const DELAY_SAMPLES: usize = SAMPLE_RATE; // one second
+const SAMPLES: usize = SAMPLE_RATE / 4; // the `Vec` is slow enough that a quarter of a second is plenty
+
+fn main() {
+    let input: Vec<f32> = (0..SAMPLES).map(|i| (i as f32 * 0.01).sin()).collect();
+
+    let mut ring_buffer = RingBuffer::new(DELAY_SAMPLES);
+    let start = Instant::now();
+    for sample in &input {
+        ring_buffer.push(*sample);
+        black_box(ring_buffer.read(DELAY_SAMPLES as f32 - 1.5));
+    }
+    let ring_buffer_time = start.elapsed();
+
+    let mut vec = vec![0.0f32; DELAY_SAMPLES];
+    let start = Instant::now();
+    for sample in &input {
+        vec.remove(0);
+        vec.push(*sample);
+        // the same linearly interpolated read, 1.5 samples from the oldest end
+        black_box(0.5 * (vec[0] + vec[1]));
+    }
+    let vec_time = start.elapsed();
+
+    println!("one-second delay line, {SAMPLES} samples:");
+    println!("  RingBuffer: {:?} per sample", ring_buffer_time / SAMPLES as u32);
+    println!("  Vec:        {:?} per sample", vec_time / SAMPLES as u32);
+}
@@ -0,0 +1,45 @@
+//! Compares rendering a constant sine a grain at a time, which takes `WaveFunction::next_grain`'s fast path,
+//! against rendering it a sample at a time. Run with `cargo bench --bench sine_grain`.
+
+use gran::{oscillator::WaveFunction, player::fix_sample_rate, sound::SAMPLES_PER_GRAIN, Number};
+use std::{hint::black_box, time::Instant};
+
+const SAMPLE_RATE: usize = 48000;
+const GRAINS: usize = 20_000;
+
+fn sine() -> WaveFunction {
+    WaveFunction::Sine {
+        frequency: Number::number(440.0),
+        amplitude: Number::number(1.0),
+        phase: Number::number(0.0),
+    }
+}
+
+fn main() {
+    fix_sample_rate(SAMPLE_RATE);
+    let dt = 1.0 / SAMPLE_RATE as f32;
+
+    let mut wave_function = sine();
+    let mut phase = 0.0;
+    let start = Instant::now();
+    for _ in 0..GRAINS {
+        black_box(wave_function.next_grain(&mut phase, dt));
+    }
+    let per_grain = start.elapsed() / GRAINS as u32;
+
+    let mut wave_function = sine();
+    let mut phase = 0.0;
+    let start = Instant::now();
+    for _ in 0..GRAINS {
+        let mut grain = [0.0; SAMPLES_PER_GRAIN];
+        for sample in &mut grain {
+            *sample = wave_function.next_value(&mut phase, dt);
+        }
+        black_box(grain);
+    }
+    let per_sample = start.elapsed() / GRAINS as u32;
+
+    println!("constant sine, {SAMPLES_PER_GRAIN}-sample grains:");
+    println!("  next_grain: {per_grain:?} per grain");
+    println!("  next_value: {per_sample:?} per grain");
+}
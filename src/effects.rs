@@ -1,9 +1,21 @@
-use crate::{Number, player::SAMPLE_RATE, sound::{EffectInput, Grain, SAMPLES_PER_GRAIN}};
+mod convolver;
+mod descriptor;
+mod history;
+mod ring_buffer;
+
+use crate::{Number, oscillator::slew_towards, player::SAMPLE_RATE, profiling, sample::{resample, try_load_samples_from_file, window, LoadError, Window}, sound::{check_number_range, check_range, CompositionError, EffectContext, Grain, SAMPLES_PER_GRAIN}};
+pub use descriptor::{DescriptorError, EffectDescriptor, EffectParameter, EffectRegistry};
+pub use history::{History, HISTORY_SAMPLES};
+pub use ring_buffer::RingBuffer;
+use convolver::Convolver;
 use std::{f32::consts::PI, fmt::Debug};
 
+/// A change an effect asks the oscillator it is on to make.
 #[derive(Debug)]
 pub enum OscillatorChange {
-    Frequency(f32),
+    Frequency(f32), // in Hz
+    Note(String), // a note name like "C#3"; invalid names are ignored
+    Semitones(f32), // shift the current frequency by this many semitones
 }
 
 pub struct EffectOutput {
@@ -13,7 +25,18 @@ pub struct EffectOutput {
 
 pub trait EffectTrait: Send + Sync + Debug {
     // fn clone_box(&self) -> Box<dyn Effect>;
-    fn apply(&mut self, input: EffectInput) -> EffectOutput;
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput;
+    /// The effect's type tag and parameters, which `EffectRegistry::build` turns back into the effect.
+    fn describe(&self) -> EffectDescriptor;
+    /// How many samples late the effect's output is compared to its input, e.g. from lookahead.
+    fn latency_samples(&self) -> usize {
+        0
+    }
+}
+
+/// The total latency of effects applied one after another.
+pub(crate) fn chain_latency(effects: &[Effect]) -> usize {
+    effects.iter().map(|effect| effect.latency_samples()).sum()
 }
 
 /// Adjusts the volume of every grain.
@@ -21,10 +44,10 @@ pub trait EffectTrait: Send + Sync + Debug {
 pub struct Volume(pub Number);
 
 impl EffectTrait for Volume {
-    fn apply(&mut self, input: EffectInput) -> EffectOutput {
+    fn apply(&mut self, grain: Grain, _context: &EffectContext) -> EffectOutput {
         let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
         for i in 0..SAMPLES_PER_GRAIN {
-            new_grain[i] = input.grain[i] * self.0.next_value();
+            new_grain[i] = grain[i] * self.0.next_value();
         }
 
         EffectOutput {
@@ -33,6 +56,10 @@ impl EffectTrait for Volume {
         }
     }
 
+    fn describe(&self) -> EffectDescriptor {
+        EffectDescriptor::new("volume").parameter("volume", EffectParameter::Number(self.0.clone()))
+    }
+
     // fn clone_box(&self) -> Box<dyn Effect> {
     //     Box::new(self.clone())
     // }
@@ -48,10 +75,13 @@ impl OnePoleFilter {
         Self { previous_output: 0.0 }
     }
 
-    fn process_sample(&mut self, sample: f32, cutoff: f32) -> f32 {
+    fn process_sample(&mut self, sample: f32, cutoff: f32, saturated: bool) -> f32 {
         let output = cutoff * sample + (1.0 - cutoff) * self.previous_output;
         self.previous_output = output;
 
+        if !saturated {
+            return output;
+        }
         let saturated = (output * 0.7).tanh() * 1.4;
 
         saturated
@@ -66,6 +96,14 @@ pub enum FilterType {
     Notch,
 }
 
+/// The default feedback gain at full resonance for 4-pole filters.
+/// An ideal 4-pole ladder self-oscillates once its feedback gain reaches 4, so at this scale low cutoffs start to ring
+/// on their own from a resonance of about 0.75 (4 / 5.5 plus a little). Because the feedback is taken a sample late,
+/// the threshold rises with the cutoff: at 48 kHz it is about 0.78 at 500 Hz and 0.96 at 2 kHz, and a few kHz higher
+/// the filter never self-oscillates. The saturation keeps the oscillation bounded; with `saturated(false)` it grows
+/// without limit.
+pub const DEFAULT_RESONANCE_SCALE: f32 = 5.5;
+
 #[derive(Clone, Debug)]
 pub struct Filter {
     mode: FilterType,
@@ -73,6 +111,8 @@ pub struct Filter {
     resonance: Number,
     poles: Vec<OnePoleFilter>,
     stage_outputs: Vec<f32>,
+    saturated: bool,
+    resonance_scale: f32,
 }
 
 impl Filter {
@@ -88,9 +128,25 @@ impl Filter {
             resonance,
             poles,
             stage_outputs: vec![0.0; num_poles + 1],
+            saturated: true,
+            resonance_scale: DEFAULT_RESONANCE_SCALE,
         }
     }
 
+    /// How much feedback a resonance of 1 gives, for 4-pole filters. Defaults to `DEFAULT_RESONANCE_SCALE`,
+    /// which also explains where the filter starts to self-oscillate.
+    pub fn resonance_scale(mut self, resonance_scale: f32) -> Self {
+        self.resonance_scale = resonance_scale;
+        self
+    }
+
+    /// Whether each pole saturates its output, for the filter's usual warm character. On by default.
+    /// Turn it off for a linear filter, e.g. for precise EQ or inside a feedback loop.
+    pub fn saturated(mut self, saturated: bool) -> Self {
+        self.saturated = saturated;
+        self
+    }
+
     pub fn new_low_pass(cutoff_frequency: Number, resonance: Number, num_poles: usize) -> Self {
         Self::new(FilterType::LowPass, cutoff_frequency, resonance, num_poles)
     }
@@ -107,20 +163,20 @@ impl Filter {
         Self::new(FilterType::Notch, cutoff_frequency, resonance, num_poles)
     }
 
-    fn process_sample(&mut self, mut sample: f32) -> f32 {
+    fn process_sample(&mut self, mut sample: f32, sample_rate: usize) -> f32 {
         self.stage_outputs[0] = sample;
 
         if self.poles.len() == 4 { // only do feedback for 4-pole filter, anything less can't be heard and anything more kills your ears
             let resonance = self.resonance.next_value();
             assert!(resonance >= 0.0 && resonance <= 1.0);
-            let feedback = 5.5 * resonance * self.poles.last().unwrap().previous_output;
+            let feedback = self.resonance_scale * resonance * self.poles.last().unwrap().previous_output;
             sample -= feedback;
         }
 
         let cutoff_frequency = self.cutoff_frequency.next_value();
-        let cutoff = 1.0 - (-2.0 * PI * cutoff_frequency / *SAMPLE_RATE as f32).exp();
+        let cutoff = 1.0 - (-2.0 * PI * cutoff_frequency / sample_rate as f32).exp();
         for (i, pole) in self.poles.iter_mut().enumerate() {
-            sample = pole.process_sample(sample, cutoff);
+            sample = pole.process_sample(sample, cutoff, self.saturated);
             self.stage_outputs[i+1] = sample;
         }
 
@@ -159,11 +215,11 @@ impl EffectTrait for Filter {
     //     Box::new(self.clone())
     // }
 
-    fn apply(&mut self, input: EffectInput) -> EffectOutput {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
         let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
 
         for i in 0..SAMPLES_PER_GRAIN {
-            new_grain[i] = self.process_sample(input.grain[i]);
+            new_grain[i] = self.process_sample(grain[i], context.sample_rate);
         }
 
         EffectOutput {
@@ -171,6 +227,49 @@ impl EffectTrait for Filter {
             oscillator_changes: Vec::new(),
         }
     }
+
+    fn describe(&self) -> EffectDescriptor {
+        let mode = match self.mode {
+            FilterType::LowPass => "low_pass",
+            FilterType::HighPass => "high_pass",
+            FilterType::BandPass => "band_pass",
+            FilterType::Notch => "notch",
+        };
+
+        EffectDescriptor::new("filter")
+            .parameter("mode", EffectParameter::Text(mode.to_string()))
+            .parameter("cutoff_frequency", EffectParameter::Number(self.cutoff_frequency.clone()))
+            .parameter("resonance", EffectParameter::Number(self.resonance.clone()))
+            .parameter("num_poles", EffectParameter::Integer(self.poles.len()))
+            .parameter("saturated", EffectParameter::Bool(self.saturated))
+            .parameter("resonance_scale", EffectParameter::Float(self.resonance_scale))
+    }
+}
+
+/// Equal-power panning of a mono signal, with the position from -1 (left) to 1 (right).
+/// Effects are mono until the stereo output path exists, so this produces frames for it rather than being an `Effect`.
+#[derive(Clone, Debug)]
+pub struct Pan {
+    position: Number,
+}
+
+impl Pan {
+    pub fn new(position: Number) -> Self {
+        Self { position }
+    }
+
+    /// Pan one sample, returning `(left, right)`. The centre is about 3 dB quieter on each side than hard left or right.
+    pub fn next_frame(&mut self, sample: f32) -> (f32, f32) {
+        let position = self.position.next_value().clamp(-1.0, 1.0);
+        let angle = (position + 1.0) * PI / 4.0; // from 0 (left) to pi/2 (right)
+
+        (sample * angle.cos(), sample * angle.sin())
+    }
+}
+
+/// The root mean square level of a grain.
+fn rms(grain: &Grain) -> f32 {
+    (grain.iter().map(|sample| sample * sample).sum::<f32>() / grain.len() as f32).sqrt()
 }
 
 /// Applies a soft saturation to the grain.
@@ -180,10 +279,18 @@ pub struct Saturation {
     actual_drive: f32,
     mix: Number,
     slew_rate: f32,
+    auto_gain: bool,
+    makeup_gain: f32,
 }
 
 impl Saturation {
     pub fn new(drive: Number, mix: Number, slew_rate: f32) -> Self {
+        Self::new_with_options(drive, mix, slew_rate, false)
+    }
+
+    /// If `auto_gain` is set, makeup gain is applied so each grain has the same RMS level as its input,
+    /// which keeps the volume steady while the drive changes.
+    pub fn new_with_options(drive: Number, mix: Number, slew_rate: f32, auto_gain: bool) -> Self {
         let mut target_drive = drive.clone();
 
         Self {
@@ -191,19 +298,19 @@ impl Saturation {
             actual_drive: target_drive.next_value() / 3.0,
             mix,
             slew_rate,
+            auto_gain,
+            makeup_gain: 1.0,
         }
     }
 
-    pub fn update_actual_drive(&mut self) {
+    pub fn update_actual_drive(&mut self, sample_rate: usize) {
         let target_drive = self.target_drive.next_value();
-        let max_change = self.slew_rate / *SAMPLE_RATE as f32;
-        let diff = target_drive - self.actual_drive;
-        let change = diff.clamp(-max_change, max_change);
-        self.actual_drive += change;
+        let max_change = self.slew_rate / sample_rate as f32;
+        self.actual_drive = slew_towards(self.actual_drive, target_drive, max_change);
     }
 
-    pub fn process_sample(&mut self, sample: f32) -> f32 {
-        self.update_actual_drive();
+    pub fn process_sample(&mut self, sample: f32, sample_rate: usize) -> f32 {
+        self.update_actual_drive(sample_rate);
 
         let drive = if sample >= 0.0 {
             self.actual_drive
@@ -228,12 +335,26 @@ impl EffectTrait for Saturation {
     //     Box::new(self.clone())
     // }
 
-    fn apply(&mut self, input: EffectInput) -> EffectOutput {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
         let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
 
         for i in 0..SAMPLES_PER_GRAIN {
-            let sample = input.grain[i];
-            new_grain[i] = self.process_sample(sample);
+            let sample = grain[i];
+            new_grain[i] = self.process_sample(sample, context.sample_rate);
+        }
+
+        if self.auto_gain {
+            let input_rms = rms(&grain);
+            let output_rms = rms(&new_grain);
+            let target_gain = if output_rms > f32::EPSILON { input_rms / output_rms } else { 1.0 };
+
+            // ramp from the previous grain's gain to avoid steps at grain boundaries
+            let start_gain = self.makeup_gain;
+            for (i, sample) in new_grain.iter_mut().enumerate() {
+                let progress = (i + 1) as f32 / SAMPLES_PER_GRAIN as f32;
+                *sample *= start_gain + (target_gain - start_gain) * progress;
+            }
+            self.makeup_gain = target_gain;
         }
 
         EffectOutput {
@@ -241,45 +362,37 @@ impl EffectTrait for Saturation {
             oscillator_changes: Vec::new(),
         }
     }
+
+    fn describe(&self) -> EffectDescriptor {
+        EffectDescriptor::new("saturation")
+            .parameter("drive", EffectParameter::Number(self.target_drive.clone()))
+            .parameter("mix", EffectParameter::Number(self.mix.clone()))
+            .parameter("slew_rate", EffectParameter::Float(self.slew_rate))
+            .parameter("auto_gain", EffectParameter::Bool(self.auto_gain))
+    }
 }
 
+/// The longest delay `validate` accepts, in seconds. Longer delays need a huge buffer and are almost always a mistake.
+const MAX_DELAY_SECS: f32 = 60.0;
+
 /// A tape delay effect for slapback, echo, etc.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TapeDelay {
-    buffer: Vec<f32>,
+    buffer: RingBuffer,
     read_delay: f32, // in seconds
-    extra_space: usize,
+    extra_delay: f32, // in seconds, to allow for wow and flutter
     mix: Number,
     feedback: Number,
+    wow_range_pct: f32,
+    wow_speed: f32,
+    flutter_range_pct: f32,
+    flutter_speed: f32,
     wow_oscillator: Number,
     flutter_oscillator: Number,
     low_pass_filter: Filter,
     saturation: Saturation,
 }
 
-impl Clone for TapeDelay {
-    fn clone(&self) -> Self {
-        let read_offset = (self.read_delay * *SAMPLE_RATE as f32) as usize;
-        let mut new_buffer = Vec::with_capacity(read_offset + self.extra_space);
-
-        for sample in &self.buffer {
-            new_buffer.insert(0, *sample);
-        }
-
-        Self {
-            buffer: new_buffer,
-            read_delay: self.read_delay,
-            extra_space: self.extra_space,
-            mix: self.mix.clone(),
-            feedback: self.feedback.clone(),
-            wow_oscillator: self.wow_oscillator.clone(),
-            flutter_oscillator: self.flutter_oscillator.clone(),
-            low_pass_filter: self.low_pass_filter.clone(),
-            saturation: self.saturation.clone(),
-        }
-    }
-}
-
 impl TapeDelay {
     pub fn light(delay: f32) -> Self {
         Self::new(
@@ -304,16 +417,17 @@ impl TapeDelay {
     ) -> Self {
         let wow_range = wow_range_pct * read_delay;
         let flutter_range = flutter_range_pct * read_delay;
-        let extra_space = ((wow_range + flutter_range) * *SAMPLE_RATE as f32) as usize; // to allow for wow and flutter
-        let read_offset = (read_delay * *SAMPLE_RATE as f32) as usize;
-        let buffer = Vec::with_capacity(read_offset + extra_space);
 
         Self {
-            buffer,
+            buffer: RingBuffer::new(0),
             read_delay,
-            extra_space,
+            extra_delay: wow_range + flutter_range,
             mix,
             feedback,
+            wow_range_pct,
+            wow_speed,
+            flutter_range_pct,
+            flutter_speed,
             wow_oscillator: Number::sine_around(0.0, wow_range, wow_speed),
             flutter_oscillator: Number::sine_around(0.0, flutter_range, flutter_speed),
             low_pass_filter: Filter::new_low_pass(Number::number(6000.0), Number::number(0.3), 1),
@@ -321,41 +435,31 @@ impl TapeDelay {
         }
     }
 
-    fn push_sample_to_buffer(&mut self, sample: f32) {
-        if self.buffer.len() >= self.buffer.capacity() - self.extra_space {
-            self.buffer.remove(0);
-        }
-
-        self.buffer.push(sample);
-    }
-
-    fn read_sample_from_buffer(&mut self) -> f32 {
-        let read_index = self.extra_space;
+    fn read_sample_from_buffer(&mut self, sample_rate: usize) -> f32 {
         let wow = self.wow_oscillator.next_value();
         let flutter = self.flutter_oscillator.next_value();
-        // convert wow and flutter from seconds to samples
-        let wow_samples = wow * *SAMPLE_RATE as f32;
-        let flutter_samples = flutter * *SAMPLE_RATE as f32;
-        let read_index = (read_index as f32 + wow_samples + flutter_samples) as usize;
+        // convert the delay from seconds to samples
+        let delay_samples = (self.read_delay + wow + flutter) * sample_rate as f32;
 
-        self.buffer[read_index]
+        self.buffer.read(delay_samples)
     }
 
-    fn process_sample(&mut self, sample: f32) -> f32 {
-        let buffer_duration = self.buffer.len() as f32 / *SAMPLE_RATE as f32;
-        let delay_sample = if buffer_duration < self.read_delay {
-            0.0
-        } else {
-            self.read_sample_from_buffer()
-        };
+    fn process_sample(&mut self, sample: f32, sample_rate: usize) -> f32 {
+        // the buffer is sized lazily, since the sample rate is only known once processing starts
+        let capacity = ((self.read_delay + self.extra_delay) * sample_rate as f32) as usize + 2;
+        if self.buffer.capacity() != capacity {
+            self.buffer = RingBuffer::new(capacity);
+        }
 
-        let processed = self.saturation.process_sample(delay_sample);
-        let processed = self.low_pass_filter.process_sample(processed);
+        let delay_sample = self.read_sample_from_buffer(sample_rate);
+
+        let processed = self.saturation.process_sample(delay_sample, sample_rate);
+        let processed = self.low_pass_filter.process_sample(processed, sample_rate);
 
         let feedback = self.feedback.next_value();
         assert!(feedback >= 0.0 && feedback <= 1.0);
         let to_buffer = sample + feedback * processed;
-        self.push_sample_to_buffer(to_buffer);
+        self.buffer.push(to_buffer);
 
         let mix = self.mix.next_value();
         assert!(mix >= 0.0 && mix <= 1.0);
@@ -370,11 +474,356 @@ impl EffectTrait for TapeDelay {
     //     Box::new(self.clone())
     // }
 
-    fn apply(&mut self, input: EffectInput) -> EffectOutput {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
         let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
 
         for i in 0..SAMPLES_PER_GRAIN {
-            new_grain[i] = self.process_sample(input.grain[i]);
+            new_grain[i] = self.process_sample(grain[i], context.sample_rate);
+        }
+
+        EffectOutput {
+            grain: new_grain,
+            oscillator_changes: Vec::new(),
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        EffectDescriptor::new("tape_delay")
+            .parameter("read_delay", EffectParameter::Float(self.read_delay))
+            .parameter("mix", EffectParameter::Number(self.mix.clone()))
+            .parameter("feedback", EffectParameter::Number(self.feedback.clone()))
+            .parameter("wow_range_pct", EffectParameter::Float(self.wow_range_pct))
+            .parameter("wow_speed", EffectParameter::Float(self.wow_speed))
+            .parameter("flutter_range_pct", EffectParameter::Float(self.flutter_range_pct))
+            .parameter("flutter_speed", EffectParameter::Float(self.flutter_speed))
+    }
+}
+
+/// A length of time measured in beats, following the tempo, or in seconds.
+#[derive(Clone, Copy, Debug)]
+pub enum Duration {
+    Beats(f32),
+    Secs(f32),
+}
+
+impl Duration {
+    /// The length in samples, or `None` for beats if there is no tempo. `Composition::validate` reports that case.
+    fn samples(self, context: &EffectContext) -> Option<usize> {
+        let secs = match self {
+            Duration::Secs(secs) => secs,
+            Duration::Beats(beats) => beats * context.secs_per_beat?,
+        };
+
+        Some((secs * context.sample_rate as f32).round().max(1.0) as usize)
+    }
+
+    /// The length in seconds, or `None` for beats if there is no tempo.
+    fn secs(self, secs_per_beat: Option<f32>) -> Option<f32> {
+        match self {
+            Duration::Secs(secs) => Some(secs),
+            Duration::Beats(beats) => Some(beats * secs_per_beat?),
+        }
+    }
+
+    /// Record an error if this is in beats and the effect using it gets no tempo.
+    fn check_tempo(self, parameter: &'static str, secs_per_beat: Option<f32>, errors: &mut Vec<CompositionError>) {
+        if matches!(self, Duration::Beats(_)) && secs_per_beat.is_none() {
+            errors.push(CompositionError::MissingTempo { parameter });
+        }
+    }
+
+    fn value_and_unit(self) -> (f32, &'static str) {
+        match self {
+            Duration::Beats(beats) => (beats, "beats"),
+            Duration::Secs(secs) => (secs, "secs"),
+        }
+    }
+
+    /// Parse a duration from a value and a unit of "beats" or "secs".
+    pub(crate) fn from_value_and_unit(value: f32, unit: &str) -> Option<Self> {
+        match unit {
+            "beats" => Some(Duration::Beats(value)),
+            "secs" => Some(Duration::Secs(value)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum StutterState {
+    Recording { recorded: usize },
+    Repeating { position: usize, repeats_left: usize },
+}
+
+/// A beat-repeat effect: records a slice of the input, then loops it `repeats` times before recording the next one.
+/// The input passes through unchanged while a slice is recorded, and is mixed with the repeats while they play.
+/// A slice length in beats needs a tempo; without one the input passes through untouched.
+#[derive(Clone, Debug)]
+pub struct Stutter {
+    slice_length: Duration,
+    repeats: usize,
+    mix: Number,
+    buffer: RingBuffer,
+    state: StutterState,
+}
+
+impl Stutter {
+    pub fn new(slice_length: Duration, repeats: usize, mix: Number) -> Self {
+        Self {
+            slice_length,
+            repeats,
+            mix,
+            buffer: RingBuffer::new(0),
+            state: StutterState::Recording { recorded: 0 },
+        }
+    }
+
+    fn process_sample(&mut self, sample: f32, context: &EffectContext) -> f32 {
+        match &mut self.state {
+            StutterState::Recording { recorded } => {
+                // the slice length is only read at the start of each slice, so tempo changes don't cut a slice short
+                if *recorded == 0 {
+                    let Some(slice_samples) = self.slice_length.samples(context) else {
+                        return sample;
+                    };
+                    if self.buffer.capacity() != slice_samples {
+                        self.buffer = RingBuffer::new(slice_samples);
+                    }
+                }
+
+                self.buffer.push(sample);
+                *recorded += 1;
+                if *recorded == self.buffer.capacity() && self.repeats > 0 {
+                    self.state = StutterState::Repeating { position: 0, repeats_left: self.repeats };
+                } else if *recorded == self.buffer.capacity() {
+                    *recorded = 0;
+                }
+
+                sample
+            },
+            StutterState::Repeating { position, repeats_left } => {
+                let slice_samples = self.buffer.capacity();
+                // the oldest sample in the buffer is the start of the slice
+                let repeated = self.buffer.read((slice_samples - 1 - *position) as f32);
+
+                *position += 1;
+                if *position == slice_samples {
+                    *position = 0;
+                    *repeats_left -= 1;
+                    if *repeats_left == 0 {
+                        self.state = StutterState::Recording { recorded: 0 };
+                    }
+                }
+
+                let mix = self.mix.next_value();
+                assert!((0.0..=1.0).contains(&mix));
+
+                mix * repeated + (1.0 - mix) * sample
+            },
+        }
+    }
+}
+
+impl EffectTrait for Stutter {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
+        let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
+        for (new_sample, sample) in new_grain.iter_mut().zip(grain) {
+            *new_sample = self.process_sample(sample, context);
+        }
+
+        EffectOutput {
+            grain: new_grain,
+            oscillator_changes: Vec::new(),
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        let (slice_length, unit) = self.slice_length.value_and_unit();
+
+        EffectDescriptor::new("stutter")
+            .parameter("slice_length", EffectParameter::Float(slice_length))
+            .parameter("slice_unit", EffectParameter::Text(unit.to_string()))
+            .parameter("repeats", EffectParameter::Integer(self.repeats))
+            .parameter("mix", EffectParameter::Number(self.mix.clone()))
+    }
+}
+
+/// The longest window `ReverseBuffer` will reverse, in seconds. Longer windows are shortened to this.
+const MAX_REVERSE_WINDOW_SECS: f32 = 10.0;
+
+/// Plays the input back reversed, a window at a time: while one window is recorded, the previous one plays backwards.
+/// The reversed audio is therefore one window behind the input, which gives reverse-reverb-style swells into each window,
+/// and is reported as the effect's latency. A window length in beats needs a tempo; without one the input passes through untouched.
+#[derive(Clone, Debug)]
+pub struct ReverseBuffer {
+    window_length: Duration,
+    mix: Number,
+    buffer: RingBuffer, // holds the window being recorded and the one being played
+    window_samples: usize,
+    position: usize, // how far through the current window playback is
+}
+
+impl ReverseBuffer {
+    pub fn new(window_length: Duration, mix: Number) -> Self {
+        Self {
+            window_length,
+            mix,
+            buffer: RingBuffer::new(0),
+            window_samples: 0,
+            position: 0,
+        }
+    }
+
+    fn process_sample(&mut self, sample: f32, context: &EffectContext) -> f32 {
+        // the window length is only read at the start of each window, so tempo changes don't cut a window short
+        if self.position == 0 {
+            let Some(window_samples) = self.window_length.samples(context) else {
+                return sample;
+            };
+            let max_samples = (MAX_REVERSE_WINDOW_SECS * context.sample_rate as f32) as usize;
+            self.window_samples = window_samples.min(max_samples);
+            if self.buffer.capacity() != 2 * self.window_samples {
+                self.buffer = RingBuffer::new(2 * self.window_samples);
+            }
+        }
+
+        self.buffer.push(sample);
+        // the previous window's sample `position` from its end is `2 * position + 1` samples ago
+        let reversed = self.buffer.read((2 * self.position + 1) as f32);
+        self.position = (self.position + 1) % self.window_samples;
+
+        let mix = self.mix.next_value();
+        assert!((0.0..=1.0).contains(&mix));
+
+        mix * reversed + (1.0 - mix) * sample
+    }
+}
+
+impl EffectTrait for ReverseBuffer {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
+        let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
+        for (new_sample, sample) in new_grain.iter_mut().zip(grain) {
+            *new_sample = self.process_sample(sample, context);
+        }
+
+        EffectOutput {
+            grain: new_grain,
+            oscillator_changes: Vec::new(),
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        let (window_length, unit) = self.window_length.value_and_unit();
+
+        EffectDescriptor::new("reverse_buffer")
+            .parameter("window_length", EffectParameter::Float(window_length))
+            .parameter("window_unit", EffectParameter::Text(unit.to_string()))
+            .parameter("mix", EffectParameter::Number(self.mix.clone()))
+    }
+
+    fn latency_samples(&self) -> usize {
+        match self.window_length {
+            Duration::Secs(secs) => (secs.min(MAX_REVERSE_WINDOW_SECS) * *SAMPLE_RATE as f32).round().max(1.0) as usize,
+            // the tempo isn't known here, so use the length of the last window, if one has started
+            Duration::Beats(_) => self.window_samples,
+        }
+    }
+}
+
+/// Holds the sound still: while frozen, the last `grain_length` seconds of input loop indefinitely instead of new input.
+///
+/// The captured audio is played by two Hann-windowed read heads half a loop apart and overlap-added, so the loop has
+/// no seam. Freezing and unfreezing crossfade (equal power) over `crossfade` seconds, and the loop keeps playing until
+/// the fade out finishes; freezing again before then picks the same loop back up rather than capturing a new one.
+#[derive(Clone, Debug)]
+pub struct Freeze {
+    grain_length: f32, // in seconds
+    crossfade: f32, // in seconds
+    frozen: bool,
+    input: RingBuffer, // the most recent input, captured when freezing
+    captured: Vec<f32>,
+    window: Vec<f32>,
+    position: usize, // of the first read head in `captured`
+    fade: f32, // 0 is all input, 1 is all loop
+}
+
+impl Freeze {
+    pub fn new(grain_length: f32, crossfade: f32) -> Self {
+        Self {
+            grain_length,
+            crossfade,
+            frozen: false,
+            input: RingBuffer::new(0),
+            captured: Vec::new(),
+            window: Vec::new(),
+            position: 0,
+            fade: 0.0,
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    fn capture(&mut self) {
+        let size = self.input.capacity();
+        // the oldest sample in the buffer is the start of the loop
+        self.captured = (0..size).map(|i| self.input.read((size - 1 - i) as f32)).collect();
+        self.window = window(Window::Hann, size);
+        self.position = 0;
+    }
+
+    fn next_looped_sample(&mut self) -> f32 {
+        let size = self.captured.len();
+        let first = self.position;
+        let second = (self.position + size / 2) % size;
+        self.position = (self.position + 1) % size;
+
+        // dividing by the window sum keeps the level steady where the windows don't add to exactly 1
+        let weight = self.window[first] + self.window[second];
+        let sum = self.captured[first] * self.window[first] + self.captured[second] * self.window[second];
+
+        sum / weight.max(f32::EPSILON)
+    }
+
+    fn process_sample(&mut self, sample: f32, sample_rate: usize) -> f32 {
+        // at least 3 samples, so the two windows never both sit at zero
+        let capacity = ((self.grain_length * sample_rate as f32) as usize).max(3);
+        if self.input.capacity() != capacity {
+            self.input = RingBuffer::new(capacity);
+        }
+
+        // always record, so there is something to freeze the moment it's asked for
+        self.input.push(sample);
+        if self.frozen && self.captured.is_empty() {
+            self.capture();
+        }
+
+        let fade_step = 1.0 / (self.crossfade * sample_rate as f32).max(1.0);
+        let target = if self.frozen { 1.0 } else { 0.0 };
+        self.fade = slew_towards(self.fade, target, fade_step);
+
+        if self.fade == 0.0 {
+            self.captured.clear();
+            return sample;
+        }
+
+        let looped = self.next_looped_sample();
+        let angle = self.fade * PI / 2.0;
+
+        looped * angle.sin() + sample * angle.cos()
+    }
+}
+
+impl EffectTrait for Freeze {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
+        let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
+        for (new_sample, sample) in new_grain.iter_mut().zip(grain) {
+            *new_sample = self.process_sample(sample, context.sample_rate);
         }
 
         EffectOutput {
@@ -382,23 +831,472 @@ impl EffectTrait for TapeDelay {
             oscillator_changes: Vec::new(),
         }
     }
+
+    fn describe(&self) -> EffectDescriptor {
+        EffectDescriptor::new("freeze")
+            .parameter("grain_length", EffectParameter::Float(self.grain_length))
+            .parameter("crossfade", EffectParameter::Float(self.crossfade))
+            .parameter("frozen", EffectParameter::Bool(self.frozen))
+    }
+}
+
+/// Convolves the input with an impulse response loaded from a .wav or .mp3 file, e.g. a recorded room for reverb
+/// or a speaker cabinet. `mix` blends from all dry at 0 to all convolved at 1.
+///
+/// The impulse response is scaled to unit energy, so the wet signal is roughly as loud as the dry one, and resampled
+/// to the playback sample rate. Enable the `rustfft` feature for FFT convolution, which long impulse responses need
+/// to run in real time; see the `convolver` module.
+#[derive(Clone, Debug)]
+pub struct Convolution {
+    path: String,
+    mix: Number,
+    impulse_response: Vec<f32>,
+    impulse_response_sample_rate: usize,
+    convolver: Option<(usize, Convolver)>, // and the sample rate it was built for
+}
+
+impl Convolution {
+    /// Load the impulse response at `path`. Panics if it can't be loaded; see `try_new`.
+    pub fn new(path: &str, mix: Number) -> Self {
+        Self::try_new(path, mix).unwrap_or_else(|err| panic!("couldn't load impulse response {path}: {err}"))
+    }
+
+    /// Load the impulse response at `path`, or return why it couldn't be loaded.
+    pub fn try_new(path: &str, mix: Number) -> Result<Self, LoadError> {
+        let (mut impulse_response, impulse_response_sample_rate) = try_load_samples_from_file(path)?;
+        let energy = impulse_response.iter().map(|sample| sample * sample).sum::<f32>().sqrt();
+        if energy > 0.0 {
+            for sample in &mut impulse_response {
+                *sample /= energy;
+            }
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            mix,
+            impulse_response,
+            impulse_response_sample_rate,
+            convolver: None,
+        })
+    }
+
+    fn convolver(&mut self, sample_rate: usize) -> &mut Convolver {
+        if self.convolver.as_ref().is_none_or(|(built_for, _)| *built_for != sample_rate) {
+            let impulse_response = resample(&self.impulse_response, self.impulse_response_sample_rate, sample_rate);
+            self.convolver = Some((sample_rate, Convolver::new(&impulse_response)));
+        }
+
+        &mut self.convolver.as_mut().unwrap().1
+    }
+}
+
+impl EffectTrait for Convolution {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
+        let wet = self.convolver(context.sample_rate).process(&grain);
+
+        let mut new_grain = [0.0; SAMPLES_PER_GRAIN];
+        for ((new_sample, dry), wet) in new_grain.iter_mut().zip(grain).zip(wet) {
+            let mix = self.mix.next_value();
+            *new_sample = mix * wet + (1.0 - mix) * dry;
+        }
+
+        EffectOutput {
+            grain: new_grain,
+            oscillator_changes: Vec::new(),
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        EffectDescriptor::new("convolution")
+            .parameter("path", EffectParameter::Text(self.path.clone()))
+            .parameter("mix", EffectParameter::Number(self.mix.clone()))
+    }
+}
+
+/// Detects the pitch of each grain and pulls the oscillator towards the nearest note in a scale.
+/// Pitch is found with the normalized square difference function (McLeod's method) over the last two grains,
+/// so pitches with at least two periods in that window (above about 94 Hz at 48 kHz) and below 2 kHz are detected.
+/// Grains with no clear pitch are left alone.
+#[derive(Clone, Debug)]
+pub struct AutoTune {
+    scale: Vec<usize>, // pitch classes in the scale, in semitones above C
+    strength: f32, // how far to move towards the target note each grain, from 0 to 1
+    previous: Option<Grain>, // the last grain, so the pitch is found over two grains
+}
+
+impl AutoTune {
+    pub fn new(scale: Vec<usize>, strength: f32) -> Self {
+        assert!(!scale.is_empty(), "the scale must have at least one note");
+        assert!((0.0..=1.0).contains(&strength), "strength must be between 0 and 1");
+
+        Self { scale, strength, previous: None }
+    }
+
+    /// Tune to the C major scale.
+    pub fn c_major(strength: f32) -> Self {
+        Self::new(vec![0, 2, 4, 5, 7, 9, 11], strength)
+    }
+
+    /// Tune to every semitone.
+    pub fn chromatic(strength: f32) -> Self {
+        Self::new((0..12).collect(), strength)
+    }
+
+    /// The frequency of the samples in Hz, or `None` if they have no clear pitch.
+    ///
+    /// The normalized square difference function is `2 * r(lag) / m(lag)`, where `r` is the autocorrelation and `m` the
+    /// energy of the two overlapping parts, so it is 1 at a perfect period whatever the lag. The period is the first
+    /// peak, after the function first goes negative, that comes within 90% of the highest peak; the shortest lags
+    /// always correlate well, so without waiting for a zero crossing every low note would be heard as the highest pitch.
+    fn detect_pitch(samples: &[f32], sample_rate: usize) -> Option<f32> {
+        let energy: f32 = samples.iter().map(|sample| sample * sample).sum();
+        if energy < f32::EPSILON {
+            return None;
+        }
+
+        let min_lag = (sample_rate / 2000).max(1); // up to 2 kHz
+        let max_lag = samples.len() / 2;
+        let nsdf: Vec<f32> = (0..max_lag)
+            .map(|lag| {
+                let overlap = samples.iter().zip(&samples[lag..]);
+                let (correlation, energy) = overlap.fold((0.0, 0.0), |(correlation, energy), (a, b)| {
+                    (correlation + a * b, energy + a * a + b * b)
+                });
+
+                if energy > 0.0 { 2.0 * correlation / energy } else { 0.0 }
+            })
+            .collect();
+
+        // the highest point of each positive lobe after the first negative one
+        let mut peaks = Vec::new();
+        let mut gone_negative = false;
+        let mut lobe_peak: Option<usize> = None;
+        for (lag, value) in nsdf.iter().enumerate().skip(1) {
+            if *value < 0.0 {
+                gone_negative = true;
+                peaks.extend(lobe_peak.take());
+            } else if gone_negative && lobe_peak.is_none_or(|peak| *value > nsdf[peak]) {
+                lobe_peak = Some(lag);
+            }
+        }
+        // a lobe cut off by the end of the search may not have reached its peak
+        peaks.extend(lobe_peak.filter(|peak| *peak < max_lag - 1));
+
+        let highest = peaks.iter().map(|peak| nsdf[*peak]).fold(0.0, f32::max);
+        let best_lag = peaks.into_iter().find(|peak| nsdf[*peak] >= 0.9 * highest)?;
+        if best_lag <= min_lag || nsdf[best_lag] < 0.5 {
+            return None;
+        }
+
+        // fit a parabola through the peak and its neighbours, for a period between whole samples
+        let (before, peak, after) = (nsdf[best_lag - 1], nsdf[best_lag], nsdf[best_lag + 1]);
+        let curvature = before - 2.0 * peak + after;
+        let offset = if curvature < 0.0 { 0.5 * (before - after) / curvature } else { 0.0 };
+
+        Some(sample_rate as f32 / (best_lag as f32 + offset))
+    }
+
+    /// The frequency of the scale note nearest to `frequency`.
+    fn nearest_scale_frequency(&self, frequency: f32) -> f32 {
+        let midi = 69.0 + 12.0 * (frequency / 440.0).log2();
+        let nearest = (midi.round() as isize - 6..=midi.round() as isize + 6)
+            .filter(|note| self.scale.contains(&(note.rem_euclid(12) as usize)))
+            .min_by(|a, b| (*a as f32 - midi).abs().total_cmp(&(*b as f32 - midi).abs()))
+            .unwrap();
+
+        440.0 * 2.0f32.powf((nearest as f32 - 69.0) / 12.0)
+    }
+}
+
+impl EffectTrait for AutoTune {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
+        let mut samples = self.previous.map_or_else(Vec::new, |previous| previous.to_vec());
+        samples.extend_from_slice(&grain);
+        self.previous = Some(grain);
+
+        let mut oscillator_changes = Vec::new();
+        if let Some(detected) = Self::detect_pitch(&samples, context.sample_rate) {
+            let target = self.nearest_scale_frequency(detected);
+            // move part of the way there, in pitch rather than Hz
+            let corrected = detected * (target / detected).powf(self.strength);
+            oscillator_changes.push(OscillatorChange::Frequency(corrected));
+        }
+
+        EffectOutput {
+            grain,
+            oscillator_changes,
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        EffectDescriptor::new("auto_tune")
+            .parameter("scale", EffectParameter::Integers(self.scale.clone()))
+            .parameter("strength", EffectParameter::Float(self.strength))
+    }
 }
 
+/// An ordered list of effects applied one after another, so a chain can be built once and reused on many sounds.
 #[derive(Clone, Debug)]
+pub struct EffectChain {
+    effects: Vec<Effect>,
+}
+
+impl EffectChain {
+    pub fn new(effects: Vec<Effect>) -> Self {
+        Self { effects }
+    }
+
+    /// Add an effect to the end of the chain.
+    pub fn effect(mut self, effect: Effect) -> Self {
+        self.effects.push(effect);
+        self
+    }
+}
+
+impl EffectTrait for EffectChain {
+    fn apply(&mut self, mut grain: Grain, context: &EffectContext) -> EffectOutput {
+        let mut oscillator_changes = Vec::new();
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            let output = profiling::indexed("effects", i, || effect.apply(grain, context));
+            grain = output.grain;
+            oscillator_changes.extend(output.oscillator_changes);
+        }
+
+        EffectOutput {
+            grain,
+            oscillator_changes,
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        let effects = self.effects.iter().map(|effect| effect.describe()).collect();
+
+        EffectDescriptor::new("chain").parameter("effects", EffectParameter::Effects(effects))
+    }
+
+    fn latency_samples(&self) -> usize {
+        chain_latency(&self.effects)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)] // effects are built once and rarely moved, so their size doesn't matter
 pub enum Effect {
     Volume(Volume),
     Filter(Filter),
     Saturation(Saturation),
     TapeDelay(TapeDelay),
+    AutoTune(AutoTune),
+    Stutter(Stutter),
+    ReverseBuffer(ReverseBuffer),
+    Freeze(Freeze),
+    Convolution(Convolution),
+    Chain(EffectChain),
+}
+
+impl Effect {
+    /// Record any broken settings. `secs_per_beat` is the tempo the effect's sound gives it, if any.
+    pub(crate) fn collect_errors(&self, secs_per_beat: Option<f32>, errors: &mut Vec<CompositionError>) {
+        match self {
+            Effect::Volume(_) | Effect::AutoTune(_) => {},
+            Effect::Chain(chain) => {
+                for effect in &chain.effects {
+                    effect.collect_errors(secs_per_beat, errors);
+                }
+            },
+            Effect::Filter(filter) => {
+                check_number_range("filter cutoff frequency", &filter.cutoff_frequency, 0.0..=f32::MAX, errors);
+                check_number_range("filter resonance", &filter.resonance, 0.0..=1.0, errors);
+                check_range("filter resonance scale", filter.resonance_scale, 0.0..=f32::MAX, errors);
+            },
+            Effect::Saturation(saturation) => {
+                check_number_range("saturation drive", &saturation.target_drive, 0.0..=f32::MAX, errors);
+                check_number_range("saturation mix", &saturation.mix, 0.0..=1.0, errors);
+            },
+            Effect::TapeDelay(tape_delay) => {
+                let delay = tape_delay.read_delay + tape_delay.extra_delay;
+                if !delay.is_finite() || delay > MAX_DELAY_SECS {
+                    errors.push(CompositionError::DelayTooLong(delay));
+                } else if tape_delay.read_delay <= 0.0 {
+                    errors.push(CompositionError::OutOfRange { parameter: "tape delay time", value: tape_delay.read_delay });
+                }
+                check_number_range("tape delay mix", &tape_delay.mix, 0.0..=1.0, errors);
+                check_number_range("tape delay feedback", &tape_delay.feedback, 0.0..=1.0, errors);
+            },
+            Effect::Stutter(stutter) => {
+                let (slice_length, _) = stutter.slice_length.value_and_unit();
+                // beats can only be checked against the buffer length when there is a tempo to convert them with
+                let slice_secs = stutter.slice_length.secs(secs_per_beat);
+                if slice_length.is_nan() || slice_length <= 0.0 {
+                    errors.push(CompositionError::OutOfRange { parameter: "stutter slice length", value: slice_length });
+                } else if let Some(slice_secs) = slice_secs.filter(|secs| *secs > MAX_DELAY_SECS) {
+                    errors.push(CompositionError::OutOfRange { parameter: "stutter slice length", value: slice_secs });
+                }
+                stutter.slice_length.check_tempo("stutter slice length", secs_per_beat, errors);
+                check_number_range("stutter mix", &stutter.mix, 0.0..=1.0, errors);
+            },
+            Effect::Freeze(freeze) => {
+                if !(freeze.grain_length > 0.0 && freeze.grain_length <= MAX_DELAY_SECS) {
+                    errors.push(CompositionError::OutOfRange { parameter: "freeze grain length", value: freeze.grain_length });
+                }
+                check_range("freeze crossfade", freeze.crossfade, 0.0..=f32::MAX, errors);
+            },
+            Effect::Convolution(convolution) => check_number_range("convolution mix", &convolution.mix, 0.0..=1.0, errors),
+            Effect::ReverseBuffer(reverse) => {
+                let (window_length, _) = reverse.window_length.value_and_unit();
+                if window_length <= 0.0 {
+                    errors.push(CompositionError::OutOfRange { parameter: "reverse buffer window length", value: window_length });
+                }
+                reverse.window_length.check_tempo("reverse buffer window length", secs_per_beat, errors);
+                check_number_range("reverse buffer mix", &reverse.mix, 0.0..=1.0, errors);
+            },
+        }
+    }
 }
 
 impl EffectTrait for Effect {
-    fn apply(&mut self, input: EffectInput) -> EffectOutput {
+    fn apply(&mut self, grain: Grain, context: &EffectContext) -> EffectOutput {
+        match self {
+            Effect::Volume(effect) => profiling::time("Effect::Volume", || effect.apply(grain, context)),
+            Effect::Filter(effect) => profiling::time("Effect::Filter", || effect.apply(grain, context)),
+            Effect::Saturation(effect) => profiling::time("Effect::Saturation", || effect.apply(grain, context)),
+            Effect::TapeDelay(effect) => profiling::time("Effect::TapeDelay", || effect.apply(grain, context)),
+            Effect::AutoTune(effect) => profiling::time("Effect::AutoTune", || effect.apply(grain, context)),
+            Effect::Stutter(effect) => profiling::time("Effect::Stutter", || effect.apply(grain, context)),
+            Effect::ReverseBuffer(effect) => profiling::time("Effect::ReverseBuffer", || effect.apply(grain, context)),
+            Effect::Freeze(effect) => profiling::time("Effect::Freeze", || effect.apply(grain, context)),
+            Effect::Convolution(effect) => profiling::time("Effect::Convolution", || effect.apply(grain, context)),
+            Effect::Chain(effect) => effect.apply(grain, context), // each effect in the chain is timed separately
+        }
+    }
+
+    fn describe(&self) -> EffectDescriptor {
+        match self {
+            Effect::Volume(effect) => effect.describe(),
+            Effect::Filter(effect) => effect.describe(),
+            Effect::Saturation(effect) => effect.describe(),
+            Effect::TapeDelay(effect) => effect.describe(),
+            Effect::AutoTune(effect) => effect.describe(),
+            Effect::Stutter(effect) => effect.describe(),
+            Effect::ReverseBuffer(effect) => effect.describe(),
+            Effect::Freeze(effect) => effect.describe(),
+            Effect::Convolution(effect) => effect.describe(),
+            Effect::Chain(effect) => effect.describe(),
+        }
+    }
+
+    fn latency_samples(&self) -> usize {
         match self {
-            Effect::Volume(effect) => effect.apply(input),
-            Effect::Filter(effect) => effect.apply(input),
-            Effect::Saturation(effect) => effect.apply(input),
-            Effect::TapeDelay(effect) => effect.apply(input),
+            Effect::Volume(effect) => effect.latency_samples(),
+            Effect::Filter(effect) => effect.latency_samples(),
+            Effect::Saturation(effect) => effect.latency_samples(),
+            Effect::TapeDelay(effect) => effect.latency_samples(),
+            Effect::AutoTune(effect) => effect.latency_samples(),
+            Effect::Stutter(effect) => effect.latency_samples(),
+            Effect::ReverseBuffer(effect) => effect.latency_samples(),
+            Effect::Freeze(effect) => effect.latency_samples(),
+            Effect::Convolution(effect) => effect.latency_samples(),
+            Effect::Chain(effect) => effect.latency_samples(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cents_between(a: f32, b: f32) -> f32 {
+        1200.0 * (a / b).log2().abs()
+    }
+
+    #[test]
+    fn auto_tune_detects_low_sine() {
+        let frequency = 130.81; // C3
+        let samples: Vec<f32> = (0..2 * SAMPLES_PER_GRAIN)
+            .map(|i| (2.0 * PI * frequency * i as f32 / 48000.0).sin())
+            .collect();
+
+        let detected = AutoTune::detect_pitch(&samples, 48000).unwrap();
+        assert!(cents_between(detected, frequency) < 5.0, "detected {detected} Hz");
+    }
+
+    #[test]
+    fn auto_tune_detects_low_sawtooth() {
+        let frequency = 164.81; // E3
+        let samples: Vec<f32> = (0..2 * SAMPLES_PER_GRAIN)
+            .map(|i| 2.0 * (frequency * i as f32 / 48000.0).fract() - 1.0)
+            .collect();
+
+        let detected = AutoTune::detect_pitch(&samples, 48000).unwrap();
+        assert!(cents_between(detected, frequency) < 5.0, "detected {detected} Hz");
+    }
+
+    #[test]
+    fn auto_tune_detects_sines_across_range() {
+        for frequency in [110.0, 164.81, 196.0, 220.0, 261.63, 440.0, 880.0, 1760.0] {
+            let samples: Vec<f32> = (0..2 * SAMPLES_PER_GRAIN)
+                .map(|i| (2.0 * PI * frequency * i as f32 / 48000.0).sin())
+                .collect();
+
+            let detected = AutoTune::detect_pitch(&samples, 48000).unwrap();
+            assert!(cents_between(detected, frequency) < 5.0, "detected {detected} Hz for {frequency} Hz");
         }
     }
+
+    #[test]
+    fn stutter_in_beats_without_tempo_passes_through() {
+        let history = History::default();
+        let context = EffectContext { sample_rate: 48000, secs_per_beat: None, time_since_start: 0.0, history: &history };
+        let mut stutter = Stutter::new(Duration::Beats(0.25), 4, Number::number(1.0));
+        let grain: Grain = std::array::from_fn(|i| (i as f32 * 0.01).sin());
+
+        assert_eq!(stutter.apply(grain, &context).grain, grain);
+    }
+
+    #[test]
+    fn beats_without_tempo_are_reported() {
+        let mut errors = Vec::new();
+        Effect::Stutter(Stutter::new(Duration::Beats(0.25), 4, Number::number(1.0))).collect_errors(None, &mut errors);
+        assert_eq!(errors, vec![CompositionError::MissingTempo { parameter: "stutter slice length" }]);
+
+        errors.clear();
+        Effect::Stutter(Stutter::new(Duration::Beats(0.25), 4, Number::number(1.0))).collect_errors(Some(0.5), &mut errors);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn stutter_slice_lengths_in_beats_are_bounded_in_seconds() {
+        let stutter = |slice_length| Effect::Stutter(Stutter::new(slice_length, 4, Number::number(1.0)));
+        let mut errors = Vec::new();
+
+        // 100 beats at 120 bpm is 50 seconds, within the buffer
+        stutter(Duration::Beats(100.0)).collect_errors(Some(0.5), &mut errors);
+        assert!(errors.is_empty());
+
+        stutter(Duration::Beats(200.0)).collect_errors(Some(0.5), &mut errors);
+        assert_eq!(errors, vec![CompositionError::OutOfRange { parameter: "stutter slice length", value: 100.0 }]);
+
+        errors.clear();
+        stutter(Duration::Secs(100.0)).collect_errors(None, &mut errors);
+        assert_eq!(errors, vec![CompositionError::OutOfRange { parameter: "stutter slice length", value: 100.0 }]);
+    }
+
+    #[test]
+    fn reverse_buffer_latency_is_one_window() {
+        crate::player::fix_sample_rate(48000);
+        let reverse = ReverseBuffer::new(Duration::Secs(0.5), Number::number(1.0));
+        assert_eq!(reverse.latency_samples(), (0.5 * *SAMPLE_RATE as f32) as usize);
+    }
+
+    #[test]
+    fn a_missing_impulse_response_is_a_descriptor_error() {
+        let descriptor = EffectDescriptor::new("convolution")
+            .parameter("path", EffectParameter::Text("no_such_impulse_response.wav".to_string()))
+            .parameter("mix", EffectParameter::Number(Number::number(0.5)));
+
+        assert!(matches!(
+            EffectRegistry::new().build(&descriptor),
+            Err(DescriptorError::InvalidValue { parameter, .. }) if parameter == "path"
+        ));
+    }
 }
@@ -0,0 +1,166 @@
+//! Convolution of a stream of grains with an impulse response, for the `Convolution` effect.
+//! With the `rustfft` feature this uses uniformly partitioned overlap-add FFT convolution: the impulse response
+//! is split into grain-long partitions, and each grain is convolved with all of them at once in the frequency domain.
+//! Without it, each sample is convolved directly, which is fine for short impulse responses such as speaker cabinets
+//! but slow for long reverbs. Either way the output has no latency.
+
+use crate::sound::{Grain, SAMPLES_PER_GRAIN};
+#[cfg(feature = "rustfft")]
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+#[cfg(feature = "rustfft")]
+use std::{collections::VecDeque, fmt::{self, Debug, Formatter}, sync::Arc};
+
+#[cfg(feature = "rustfft")]
+#[derive(Clone)]
+pub(crate) struct Convolver {
+    fft: Arc<dyn Fft<f32>>,
+    inverse_fft: Arc<dyn Fft<f32>>,
+    partitions: Vec<Vec<Complex<f32>>>, // the spectrum of each grain-long slice of the impulse response
+    inputs: VecDeque<Vec<Complex<f32>>>, // the spectra of the most recent input grains, newest first
+    overlap: Vec<f32>, // the second half of the last block, which spills into the next grain
+}
+
+#[cfg(feature = "rustfft")]
+impl Convolver {
+    pub(crate) fn new(impulse_response: &[f32]) -> Self {
+        // blocks of two grains, so a grain convolved with a grain-long partition doesn't wrap around
+        let size = 2 * SAMPLES_PER_GRAIN;
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(size);
+        let inverse_fft = planner.plan_fft_inverse(size);
+
+        let partitions: Vec<_> = impulse_response
+            .chunks(SAMPLES_PER_GRAIN)
+            .map(|partition| {
+                let mut spectrum = padded_spectrum(partition, size);
+                fft.process(&mut spectrum);
+                spectrum
+            })
+            .collect();
+        let inputs = vec![vec![Complex::new(0.0, 0.0); size]; partitions.len()].into();
+
+        Self {
+            fft,
+            inverse_fft,
+            partitions,
+            inputs,
+            overlap: vec![0.0; SAMPLES_PER_GRAIN],
+        }
+    }
+
+    pub(crate) fn process(&mut self, grain: &Grain) -> Grain {
+        let size = 2 * SAMPLES_PER_GRAIN;
+        let mut spectrum = padded_spectrum(grain, size);
+        self.fft.process(&mut spectrum);
+        self.inputs.pop_back();
+        self.inputs.push_front(spectrum);
+
+        // each partition is heard as many grains late as its position in the impulse response
+        let mut block = vec![Complex::new(0.0, 0.0); size];
+        for (input, partition) in self.inputs.iter().zip(&self.partitions) {
+            for ((sum, input), partition) in block.iter_mut().zip(input).zip(partition) {
+                *sum += input * partition;
+            }
+        }
+        self.inverse_fft.process(&mut block);
+
+        // rustfft doesn't normalize, so the round trip scales by the block size
+        let scale = 1.0 / size as f32;
+        let mut output = [0.0; SAMPLES_PER_GRAIN];
+        for (i, sample) in output.iter_mut().enumerate() {
+            *sample = block[i].re * scale + self.overlap[i];
+            self.overlap[i] = block[SAMPLES_PER_GRAIN + i].re * scale;
+        }
+
+        output
+    }
+}
+
+#[cfg(feature = "rustfft")]
+fn padded_spectrum(samples: &[f32], size: usize) -> Vec<Complex<f32>> {
+    let mut spectrum = vec![Complex::new(0.0, 0.0); size];
+    for (bin, sample) in spectrum.iter_mut().zip(samples) {
+        bin.re = *sample;
+    }
+
+    spectrum
+}
+
+#[cfg(feature = "rustfft")]
+impl Debug for Convolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Convolver")
+            .field("partitions", &self.partitions.len())
+            .finish()
+    }
+}
+
+#[cfg(not(feature = "rustfft"))]
+pub(crate) type Convolver = DirectConvolver;
+
+// also built for tests with the `rustfft` feature, to check the two agree
+#[cfg(any(not(feature = "rustfft"), test))]
+#[derive(Clone, Debug)]
+pub(crate) struct DirectConvolver {
+    impulse_response: Vec<f32>,
+    input: Vec<f32>, // the most recent input, as long as the impulse response
+    write_index: usize,
+}
+
+#[cfg(any(not(feature = "rustfft"), test))]
+impl DirectConvolver {
+    pub(crate) fn new(impulse_response: &[f32]) -> Self {
+        Self {
+            impulse_response: impulse_response.to_vec(),
+            input: vec![0.0; impulse_response.len()],
+            write_index: 0,
+        }
+    }
+
+    pub(crate) fn process(&mut self, grain: &Grain) -> Grain {
+        let mut output = [0.0; SAMPLES_PER_GRAIN];
+        let len = self.input.len();
+        if len == 0 {
+            return output;
+        }
+
+        for (output, sample) in output.iter_mut().zip(grain) {
+            self.input[self.write_index] = *sample;
+
+            // walk back through the input while walking forward through the impulse response
+            *output = self.impulse_response
+                .iter()
+                .enumerate()
+                .map(|(ago, weight)| weight * self.input[(self.write_index + len - ago) % len])
+                .sum();
+
+            self.write_index = (self.write_index + 1) % len;
+        }
+
+        output
+    }
+}
+
+#[cfg(all(test, feature = "rustfft"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fft_and_direct_convolution_agree() {
+        // longer than a grain, so it spans more than one partition
+        let impulse_response: Vec<f32> = (0..SAMPLES_PER_GRAIN + SAMPLES_PER_GRAIN / 2)
+            .map(|i| (i as f32 * 0.37).sin() * 0.999f32.powi(i as i32))
+            .collect();
+        let mut fft = Convolver::new(&impulse_response);
+        let mut direct = DirectConvolver::new(&impulse_response);
+
+        for grain_index in 0..4 {
+            let grain: Grain = std::array::from_fn(|i| ((grain_index * SAMPLES_PER_GRAIN + i) as f32 * 0.05).sin());
+            let fft_output = fft.process(&grain);
+            let direct_output = direct.process(&grain);
+            for (i, (fft, direct)) in fft_output.iter().zip(direct_output).enumerate() {
+                assert!((fft - direct).abs() < 1e-3, "sample {i} of grain {grain_index}: {fft} vs {direct}");
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use super::{AutoTune, Convolution, DEFAULT_RESONANCE_SCALE, Duration, Effect, EffectChain, Filter, FilterType, Freeze, ReverseBuffer, Saturation, Stutter, TapeDelay, Volume};
+use crate::Number;
+use std::{collections::HashMap, error::Error, fmt::{self, Display, Formatter}};
+
+/// The value of one effect parameter.
+#[derive(Clone, Debug)]
+pub enum EffectParameter {
+    Number(Number),
+    Float(f32),
+    Integer(usize),
+    Integers(Vec<usize>),
+    Bool(bool),
+    Text(String),
+    Effects(Vec<EffectDescriptor>),
+}
+
+/// A stable type tag and the parameters needed to rebuild an effect, for saving presets.
+/// Rebuild the effect with `EffectRegistry::build`.
+#[derive(Clone, Debug)]
+pub struct EffectDescriptor {
+    pub name: String,
+    pub parameters: Vec<(String, EffectParameter)>,
+}
+
+impl EffectDescriptor {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), parameters: Vec::new() }
+    }
+
+    pub fn parameter(mut self, name: &str, value: EffectParameter) -> Self {
+        self.parameters.push((name.to_string(), value));
+        self
+    }
+
+    fn get(&self, parameter: &str) -> Result<&EffectParameter, DescriptorError> {
+        self.parameters
+            .iter()
+            .find(|(name, _)| name == parameter)
+            .map(|(_, value)| value)
+            .ok_or_else(|| DescriptorError::MissingParameter { effect: self.name.clone(), parameter: parameter.to_string() })
+    }
+
+    fn wrong_type(&self, parameter: &str) -> DescriptorError {
+        DescriptorError::WrongParameterType { effect: self.name.clone(), parameter: parameter.to_string() }
+    }
+
+    pub fn number(&self, parameter: &str) -> Result<Number, DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Number(number) => Ok(number.clone()),
+            EffectParameter::Float(value) => Ok(Number::number(*value)),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    pub fn float(&self, parameter: &str) -> Result<f32, DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Float(value) => Ok(*value),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    pub fn integer(&self, parameter: &str) -> Result<usize, DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Integer(value) => Ok(*value),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    pub fn integers(&self, parameter: &str) -> Result<Vec<usize>, DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Integers(values) => Ok(values.clone()),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    pub fn bool(&self, parameter: &str) -> Result<bool, DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Bool(value) => Ok(*value),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    pub fn effects(&self, parameter: &str) -> Result<&[EffectDescriptor], DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Effects(descriptors) => Ok(descriptors),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    pub fn text(&self, parameter: &str) -> Result<&str, DescriptorError> {
+        match self.get(parameter)? {
+            EffectParameter::Text(value) => Ok(value),
+            _ => Err(self.wrong_type(parameter)),
+        }
+    }
+
+    /// A duration stored as a float parameter and a text unit parameter.
+    fn duration(&self, parameter: &str, unit_parameter: &str) -> Result<Duration, DescriptorError> {
+        Duration::from_value_and_unit(self.float(parameter)?, self.text(unit_parameter)?)
+            .ok_or_else(|| DescriptorError::InvalidValue { effect: self.name.clone(), parameter: unit_parameter.to_string() })
+    }
+}
+
+/// An error from rebuilding an effect from a descriptor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DescriptorError {
+    UnknownEffect(String),
+    MissingParameter { effect: String, parameter: String },
+    WrongParameterType { effect: String, parameter: String },
+    InvalidValue { effect: String, parameter: String },
+}
+
+impl Display for DescriptorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::UnknownEffect(name) => write!(f, "unknown effect `{name}`"),
+            DescriptorError::MissingParameter { effect, parameter } => write!(f, "effect `{effect}` is missing parameter `{parameter}`"),
+            DescriptorError::WrongParameterType { effect, parameter } => write!(f, "parameter `{parameter}` of effect `{effect}` has the wrong type"),
+            DescriptorError::InvalidValue { effect, parameter } => write!(f, "parameter `{parameter}` of effect `{effect}` has an invalid value"),
+        }
+    }
+}
+
+impl Error for DescriptorError {}
+
+type EffectConstructor = Box<dyn Fn(&EffectDescriptor) -> Result<Effect, DescriptorError> + Send + Sync>;
+
+/// Rebuilds effects from their descriptors, by name. The built-in effects are registered by `new`.
+pub struct EffectRegistry {
+    constructors: HashMap<String, EffectConstructor>,
+}
+
+impl EffectRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self { constructors: HashMap::new() };
+        registry.register("volume", |descriptor| Ok(Effect::Volume(Volume(descriptor.number("volume")?))));
+        registry.register("filter", |descriptor| {
+            let mode = match descriptor.text("mode")? {
+                "low_pass" => FilterType::LowPass,
+                "high_pass" => FilterType::HighPass,
+                "band_pass" => FilterType::BandPass,
+                "notch" => FilterType::Notch,
+                _ => return Err(DescriptorError::InvalidValue { effect: descriptor.name.clone(), parameter: "mode".to_string() }),
+            };
+
+            // descriptors saved before these could be changed don't have them
+            let saturated = match descriptor.bool("saturated") {
+                Err(DescriptorError::MissingParameter { .. }) => true,
+                saturated => saturated?,
+            };
+            let resonance_scale = match descriptor.float("resonance_scale") {
+                Err(DescriptorError::MissingParameter { .. }) => DEFAULT_RESONANCE_SCALE,
+                resonance_scale => resonance_scale?,
+            };
+
+            Ok(Effect::Filter(Filter::new(
+                mode,
+                descriptor.number("cutoff_frequency")?,
+                descriptor.number("resonance")?,
+                descriptor.integer("num_poles")?,
+            ).saturated(saturated).resonance_scale(resonance_scale)))
+        });
+        registry.register("saturation", |descriptor| {
+            Ok(Effect::Saturation(Saturation::new_with_options(
+                descriptor.number("drive")?,
+                descriptor.number("mix")?,
+                descriptor.float("slew_rate")?,
+                descriptor.bool("auto_gain")?,
+            )))
+        });
+        registry.register("tape_delay", |descriptor| {
+            Ok(Effect::TapeDelay(TapeDelay::new(
+                descriptor.float("read_delay")?,
+                descriptor.number("mix")?,
+                descriptor.number("feedback")?,
+                descriptor.float("wow_range_pct")?,
+                descriptor.float("wow_speed")?,
+                descriptor.float("flutter_range_pct")?,
+                descriptor.float("flutter_speed")?,
+            )))
+        });
+        registry.register("auto_tune", |descriptor| {
+            let scale = descriptor.integers("scale")?;
+            let strength = descriptor.float("strength")?;
+            if scale.is_empty() {
+                return Err(DescriptorError::InvalidValue { effect: descriptor.name.clone(), parameter: "scale".to_string() });
+            }
+            if !(0.0..=1.0).contains(&strength) {
+                return Err(DescriptorError::InvalidValue { effect: descriptor.name.clone(), parameter: "strength".to_string() });
+            }
+
+            Ok(Effect::AutoTune(AutoTune::new(scale, strength)))
+        });
+        registry.register("stutter", |descriptor| {
+            let slice_length = descriptor.duration("slice_length", "slice_unit")?;
+
+            Ok(Effect::Stutter(Stutter::new(slice_length, descriptor.integer("repeats")?, descriptor.number("mix")?)))
+        });
+        registry.register("freeze", |descriptor| {
+            let mut freeze = Freeze::new(descriptor.float("grain_length")?, descriptor.float("crossfade")?);
+            freeze.set_frozen(descriptor.bool("frozen")?);
+
+            Ok(Effect::Freeze(freeze))
+        });
+        registry.register("reverse_buffer", |descriptor| {
+            let window_length = descriptor.duration("window_length", "window_unit")?;
+
+            Ok(Effect::ReverseBuffer(ReverseBuffer::new(window_length, descriptor.number("mix")?)))
+        });
+        registry.register("convolution", |descriptor| {
+            let convolution = Convolution::try_new(descriptor.text("path")?, descriptor.number("mix")?)
+                .map_err(|_| DescriptorError::InvalidValue { effect: descriptor.name.clone(), parameter: "path".to_string() })?;
+
+            Ok(Effect::Convolution(convolution))
+        });
+
+        registry
+    }
+
+    /// Add a way to build effects with the given name, replacing any existing one.
+    pub fn register(
+        &mut self,
+        name: &str,
+        constructor: impl Fn(&EffectDescriptor) -> Result<Effect, DescriptorError> + Send + Sync + 'static,
+    ) {
+        self.constructors.insert(name.to_string(), Box::new(constructor));
+    }
+
+    /// Chains are built here rather than registered, since their effects are built by this registry too.
+    pub fn build(&self, descriptor: &EffectDescriptor) -> Result<Effect, DescriptorError> {
+        if descriptor.name == "chain" {
+            let effects = descriptor
+                .effects("effects")?
+                .iter()
+                .map(|effect| self.build(effect))
+                .collect::<Result<_, _>>()?;
+
+            return Ok(Effect::Chain(EffectChain::new(effects)));
+        }
+
+        let constructor = self.constructors
+            .get(&descriptor.name)
+            .ok_or_else(|| DescriptorError::UnknownEffect(descriptor.name.clone()))?;
+
+        constructor(descriptor)
+    }
+}
+
+impl Default for EffectRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
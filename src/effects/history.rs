@@ -0,0 +1,59 @@
+use super::RingBuffer;
+use crate::sound::{Grain, SAMPLES_PER_GRAIN};
+
+/// How many recent dry samples each sound keeps for its effects.
+pub const HISTORY_SAMPLES: usize = 4 * SAMPLES_PER_GRAIN;
+
+/// The recent dry signal of a sound, before any of its effects, so effects can look back across grain boundaries
+/// without keeping buffers of their own. It holds the grains before the one being processed.
+/// Sounds keep `HISTORY_SAMPLES` by default; their builders' `history_samples` keeps more for effects that look further back.
+#[derive(Clone, Debug)]
+pub struct History {
+    buffer: RingBuffer,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self { buffer: RingBuffer::new(capacity) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// The dry sample `ago` samples before the current grain, where 0 is the last sample of the previous grain.
+    /// Samples older than the history's capacity read as the oldest one kept.
+    pub fn get(&self, ago: usize) -> f32 {
+        self.buffer.read(ago as f32)
+    }
+
+    /// The `len` samples ending `offset` samples before the current grain, oldest first,
+    /// or `None` if that reaches back further than the history holds.
+    pub fn window(&self, offset: usize, len: usize) -> Option<Vec<f32>> {
+        if offset + len > self.capacity() {
+            return None;
+        }
+
+        Some((0..len).rev().map(|i| self.get(offset + i)).collect())
+    }
+
+    /// The previous grain, or `None` if the history is shorter than a grain.
+    pub fn last_grain(&self) -> Option<Grain> {
+        let window = self.window(0, SAMPLES_PER_GRAIN)?;
+
+        Some(std::array::from_fn(|i| window[i]))
+    }
+
+    /// Record a dry grain once it has been processed.
+    pub(crate) fn push_grain(&mut self, grain: &Grain) {
+        for sample in grain {
+            self.buffer.push(*sample);
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(HISTORY_SAMPLES)
+    }
+}
@@ -0,0 +1,49 @@
+/// A fixed-size circular buffer of samples, for delay lines.
+/// Pushing is O(1), and reads can be at fractional delays using linear interpolation.
+#[derive(Clone, Debug)]
+pub struct RingBuffer {
+    samples: Vec<f32>,
+    write_index: usize, // where the next sample will be written
+}
+
+impl RingBuffer {
+    /// Create a silent buffer that remembers the last `capacity` samples.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: vec![0.0; capacity.max(1)],
+            write_index: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Push a sample, overwriting the oldest one.
+    pub fn push(&mut self, sample: f32) {
+        self.samples[self.write_index] = sample;
+        self.write_index = (self.write_index + 1) % self.samples.len();
+    }
+
+    /// Read the sample pushed `delay` samples ago, where 0 is the most recent one.
+    /// Fractional delays are linearly interpolated. The delay is clamped to the buffer's capacity.
+    pub fn read(&self, delay: f32) -> f32 {
+        let max_delay = (self.samples.len() - 1) as f32;
+        let delay = delay.clamp(0.0, max_delay);
+
+        let whole = delay.floor() as usize;
+        let fraction = delay - whole as f32;
+
+        let newer = self.read_whole(whole);
+        let older = self.read_whole((whole + 1).min(self.samples.len() - 1));
+
+        newer + fraction * (older - newer)
+    }
+
+    fn read_whole(&self, delay: usize) -> f32 {
+        let len = self.samples.len();
+        let index = (self.write_index + len - 1 - delay) % len;
+
+        self.samples[index]
+    }
+}
@@ -3,6 +3,7 @@ pub mod player;
 pub mod effects;
 pub mod oscillator;
 pub mod sample;
+pub mod profiling;
 
-pub use player::play_sound;
+pub use player::{bounce, play_sound, BounceError, start_shared_sound, start_sound, start_sound_with_config, stop_playing, PlaybackHandle, PlayerConfig};
 pub use oscillator::Number;
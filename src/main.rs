@@ -1,43 +1,53 @@
 #![warn(clippy::all, clippy::pedantic, unused_crate_dependencies)]
 
 use gran::{
-    effects::{Effect, Filter, Saturation, TapeDelay, Volume}, oscillator::{note, OscillatorBuilder, OscillatorInput, OscillatorInputAtTime, OscillatorInputIteratorBuilder, WaveFunction, ADSR}, play_sound, sample::{SampleBuilder, SampleInput, SampleInputAtTime, SampleInputIterator, SampleInputIteratorBuilder}, sound::{CompositionBuilder, Sound}, Number
+    bounce, effects::{Effect, Filter, Saturation, TapeDelay, Volume}, oscillator::{OscillatorBuilder, OscillatorInputAtTime, OscillatorInputIteratorBuilder, WaveFunction, ADSR}, play_sound, player::has_output_device, sample::{SampleBuilder, SampleInput, SampleInputAtTime, SampleInputIterator, SampleInputIteratorBuilder}, sound::{CompositionBuilder, Sound}, Number
 };
 
+/// Where to write the example when not playing it live.
+struct RenderOptions {
+    path: String,
+    secs: f32,
+}
+
+const DEFAULT_RENDER_PATH: &str = "out.wav";
+const DEFAULT_RENDER_SECS: f32 = 8.0;
+const RENDER_SAMPLE_RATE: usize = 48000;
+
+/// Reads `--render <path>` and `--secs <n>`. Rendering is also chosen when there is no output device to play on.
+fn render_options() -> Option<RenderOptions> {
+    let mut path = None;
+    let mut secs = DEFAULT_RENDER_SECS;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--render" => path = Some(args.next().expect("--render needs a path")),
+            "--secs" => secs = args.next().and_then(|secs| secs.parse().ok()).expect("--secs needs a number"),
+            _ => panic!("unknown argument `{arg}`; expected --render <path> or --secs <n>"),
+        }
+    }
+
+    if path.is_none() && !has_output_device() {
+        eprintln!("No output device found, rendering to {DEFAULT_RENDER_PATH} instead");
+        path = Some(DEFAULT_RENDER_PATH.to_string());
+    }
+
+    path.map(|path| RenderOptions { path, secs })
+}
+
 fn main() {
+    let render = render_options();
+
     let inputs = OscillatorInputIteratorBuilder::new()
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Press(note("C3")),
-            time: 0.0,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Release,
-            time: 0.3,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Press(note("E3")),
-            time: 0.5,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Release,
-            time: 0.75,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Press(note("E3")),
-            time: 1.0,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Release,
-            time: 1.25,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Press(note("E3")),
-            time: 1.5,
-        })
-        .input(OscillatorInputAtTime {
-            input: OscillatorInput::Release,
-            time: 1.75,
-        })
+        .input(OscillatorInputAtTime::press("C3", 0.0).unwrap())
+        .input(OscillatorInputAtTime::release(0.3))
+        .input(OscillatorInputAtTime::press("E3", 0.5).unwrap())
+        .input(OscillatorInputAtTime::release(0.75))
+        .input(OscillatorInputAtTime::press("E3", 1.0).unwrap())
+        .input(OscillatorInputAtTime::release(1.25))
+        .input(OscillatorInputAtTime::press("E3", 1.5).unwrap())
+        .input(OscillatorInputAtTime::release(1.75))
         .repeat_after(0.25)
         .build();
 
@@ -65,13 +75,7 @@ fn main() {
 
     let pink_noise = OscillatorBuilder::new()
         .wave_function(WaveFunction::pink_noise(Number::number(0.005), 10))
-        .inputs(OscillatorInputIteratorBuilder::new()
-            .input(OscillatorInputAtTime {
-                input: OscillatorInput::PressSame,
-                time: 0.0,
-            })
-            .build()
-        )
+        .auto_play()
         .build();
 
     let mut composition = CompositionBuilder::new()
@@ -80,5 +84,8 @@ fn main() {
         .effect(Effect::TapeDelay(TapeDelay::light(0.05)))
         .build();
 
-    play_sound(&mut composition);
+    match render {
+        Some(RenderOptions { path, secs }) => bounce(composition, secs, RENDER_SAMPLE_RATE, &path).unwrap(),
+        None => play_sound(&mut composition),
+    }
 }
@@ -1,29 +1,77 @@
 mod lfo;
 mod input;
-
-use crate::{effects::{Effect, EffectTrait, OscillatorChange}, player::SAMPLE_RATE, sound::{EffectInput, Grain, SoundTrait, SAMPLES_PER_GRAIN}};
-pub use lfo::{Number, WaveFunction};
+mod drift;
+mod note_envelope;
+mod beat_clock;
+mod spec;
+
+use crate::{effects::{chain_latency, Effect, EffectTrait, History, OscillatorChange, Volume, HISTORY_SAMPLES}, player::SAMPLE_RATE, profiling, sound::{check_number_range, check_range, CompositionError, EffectContext, Grain, SoundTrait, SAMPLES_PER_GRAIN}};
+pub use lfo::{Curve, CustomWave, EnvelopeFollower, Number, NyquistPolicy, WaveFunction, MIN_DUTY_CYCLE};
+pub(crate) use lfo::{seed_noise, slew_towards};
+pub use drift::Drift;
+pub use beat_clock::{BeatClock, Retrigger};
+pub use spec::SpecParseError;
+pub use note_envelope::{NoteEnvelope, NoteGate};
 pub use input::{OscillatorInput, OscillatorInputAtTime, OscillatorInputIterator, OscillatorInputIteratorBuilder};
+use std::{error::Error, f32::consts::PI, fmt::{self, Display, Formatter}};
+
+/// An error from parsing a note name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NoteParseError {
+    MissingOctave(String),
+    UnknownNote(String),
+}
+
+impl Display for NoteParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NoteParseError::MissingOctave(note_name) => write!(f, "note `{note_name}` has no octave"),
+            NoteParseError::UnknownNote(note_name) => write!(f, "unknown note `{note_name}`"),
+        }
+    }
+}
+
+impl Error for NoteParseError {}
 
 /// Convert a note name to a frequency in Hz.
 /// `note_name` is a string like "A4", "C#3", etc.
 /// The octave must be given. Only sharp notes are supported, not flats.
-pub fn note(note_name: &str) -> f32 {
-    let octave = note_name.chars().last().unwrap().to_digit(10).unwrap() as isize;
-    let note_name = note_name.chars().take(note_name.len() - 1).collect::<String>();
+pub fn try_note(note_name: &str) -> Result<f32, NoteParseError> {
+    let octave = note_name
+        .chars()
+        .last()
+        .and_then(|c| c.to_digit(10))
+        .ok_or_else(|| NoteParseError::MissingOctave(note_name.to_string()))? as isize;
+    let name = &note_name[..note_name.len() - 1];
 
     let notes = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
-    let note_index = notes.iter().position(|note| *note == note_name).unwrap() as isize;
+    let note_index = notes
+        .iter()
+        .position(|note| *note == name)
+        .ok_or_else(|| NoteParseError::UnknownNote(note_name.to_string()))? as isize;
     let diff_from_a_within_octave = note_index - 9;
     let diff_from_a_octaves = octave - 4;
     let diff_semitones = diff_from_a_within_octave + diff_from_a_octaves * 12;
 
     let freq = 440.0 * 2.0f32.powf(diff_semitones as f32 / 12.0);
 
-    freq
+    Ok(freq)
 }
 
-/// Attack-decay-sustain-release envelope settings for an oscillator.
+/// Convert a note name to a frequency in Hz, panicking if it is invalid. See `try_note`.
+pub fn note(note_name: &str) -> f32 {
+    try_note(note_name).unwrap()
+}
+
+/// Convert several note names to frequencies in Hz, for use with `OscillatorInput::PressChord`.
+pub fn chord(note_names: &[&str]) -> Vec<f32> {
+    note_names.iter().map(|note_name| note(note_name)).collect()
+}
+
+/// The shortest an attack or release can be, in samples. Shorter ones, including 0, are stretched to this so they don't click.
+const MIN_RAMP_SAMPLES: f32 = 32.0;
+
+/// Attack-decay-sustain-release envelope settings for an oscillator or sample.
 #[derive(Clone, Debug)]
 pub struct ADSR {
     pub attack_duration: f32, // in seconds
@@ -36,6 +84,42 @@ impl ADSR {
     pub fn new(attack_duration: f32, decay_duration: f32, sustain_amplitude_multiplier: f32, release_duration: f32) -> Self {
         Self { attack_duration, decay_duration, sustain_amplitude_multiplier, release_duration }
     }
+
+    /// The amplitude `secs` seconds after the note starts, through the attack, decay and sustain.
+    /// A decay lasting 0 seconds is skipped, jumping straight to the sustain level. The attack lasts at least `MIN_RAMP_SAMPLES`.
+    pub fn held_amplitude(&self, secs: f32) -> f32 {
+        let attack_duration = self.attack_duration.max(min_ramp_secs());
+        let decay_start = attack_duration;
+        let sustain_start = decay_start + self.decay_duration;
+
+        // a stage is only entered while `secs` is before its end, so its duration is never 0 when dividing by it
+        if secs < decay_start {
+            secs / attack_duration
+        } else if secs < sustain_start {
+            let decay_progress = (secs - decay_start) / self.decay_duration;
+            let diff = 1.0 - self.sustain_amplitude_multiplier;
+
+            1.0 - diff * decay_progress
+        } else {
+            self.sustain_amplitude_multiplier
+        }
+    }
+
+    /// The amplitude `secs` seconds into the release, fading from `start_amplitude`,
+    /// or `None` once the release has finished. The release lasts at least `MIN_RAMP_SAMPLES`.
+    pub fn release_amplitude(&self, start_amplitude: f32, secs: f32) -> Option<f32> {
+        let release_duration = self.release_duration.max(min_ramp_secs());
+        if secs >= release_duration {
+            return None;
+        }
+        let release_progress = secs / release_duration;
+
+        Some(start_amplitude * (1.0 - release_progress))
+    }
+}
+
+fn min_ramp_secs() -> f32 {
+    MIN_RAMP_SAMPLES / *SAMPLE_RATE as f32
 }
 
 #[derive(Clone, Debug)]
@@ -49,6 +133,13 @@ pub enum OscillatorState {
     },
 }
 
+/// An extra voice playing one of the tones of a chord.
+#[derive(Clone, Debug)]
+struct ChordVoice {
+    wave_function: WaveFunction,
+    phase: f32,
+}
+
 #[derive(Debug)]
 pub struct Oscillator {
     wave_function: Box<WaveFunction>,
@@ -61,29 +152,59 @@ pub struct Oscillator {
     pub state: OscillatorState,
     secs_since_start: f32,
     adsr: ADSR,
+    chord_voices: Vec<ChordVoice>, // the chord tones after the first, which shares the main wave
+    drift: Option<Drift>,
+    soft_clip: bool,
+    nyquist_policy: NyquistPolicy,
+    note_gate: Option<NoteGate>,
+    history: History,
 }
 
 impl Oscillator {
     fn apply_change(&mut self, change: OscillatorChange) {
         match change {
-            OscillatorChange::Frequency(freq) => {
-                match self.wave_function.as_mut() {
-                    WaveFunction::Sine { frequency, .. } => *frequency = Number::number(freq),
-                    WaveFunction::Square { frequency, .. } => *frequency = Number::number(freq),
-                    WaveFunction::Triangle { frequency, .. } => *frequency = Number::number(freq),
-                    WaveFunction::Sawtooth { frequency, .. } => *frequency = Number::number(freq),
-                    WaveFunction::WhiteNoise { .. } | WaveFunction::PinkNoise { .. } => {},
+            OscillatorChange::Frequency(freq) => self.wave_function.set_frequency(freq),
+            OscillatorChange::Note(note_name) => {
+                if let Ok(freq) = try_note(&note_name) {
+                    self.wave_function.set_frequency(freq);
+                }
+            },
+            OscillatorChange::Semitones(semitones) => {
+                let ratio = 2.0f32.powf(semitones / 12.0);
+                self.wave_function.scale_frequency(ratio);
+                for voice in &mut self.chord_voices {
+                    voice.wave_function.scale_frequency(ratio);
                 }
             },
         }
     }
 
     fn handle_input(&mut self, input: OscillatorInput) {
+        if let Some(gate) = &self.note_gate {
+            match input {
+                OscillatorInput::Release => gate.release(),
+                OscillatorInput::Press(_) | OscillatorInput::PressChord(_) | OscillatorInput::PressSame => gate.press(),
+            }
+        }
+
         match input {
             OscillatorInput::Press(freq) => {
+                self.chord_voices.clear();
                 self.apply_change(OscillatorChange::Frequency(freq));
                 self.state = OscillatorState::Play { started_at: self.secs_since_start };
             },
+            OscillatorInput::PressChord(frequencies) => {
+                self.chord_voices.clear();
+                if let Some((first, rest)) = frequencies.split_first() {
+                    self.apply_change(OscillatorChange::Frequency(*first));
+                    for freq in rest {
+                        let mut wave_function = self.wave_function.as_ref().clone();
+                        wave_function.set_frequency(*freq);
+                        self.chord_voices.push(ChordVoice { wave_function, phase: 0.0 });
+                    }
+                }
+                self.state = OscillatorState::Play { started_at: self.secs_since_start };
+            },
             OscillatorInput::Release => {
                 self.index = 0;
                 self.state = OscillatorState::Release { started_at: self.secs_since_start };
@@ -98,9 +219,99 @@ impl Oscillator {
         }
     }
 
+    /// The frequency the oscillator is producing, including any drift, or `None` for noise.
+    /// Modulated frequencies are read from a copy of their modulator, so this doesn't disturb playback.
+    /// With a chord playing, this is the first note's frequency.
+    pub fn current_frequency(&self) -> Option<f32> {
+        let frequency = self.wave_function.frequency()?;
+        let base = frequency.constant_value().unwrap_or_else(|| frequency.clone().next_value());
+        let drift_ratio = self.drift.as_ref().map_or(1.0, Drift::current_ratio);
+
+        Some(base * drift_ratio)
+    }
+
+    /// Start a note at `freq` Hz now, as a scheduled `OscillatorInput::Press` would. For live control, e.g. from MIDI.
+    pub fn press(&mut self, freq: f32) {
+        self.handle_input(OscillatorInput::Press(freq));
+    }
+
+    /// Start the release of the current note now.
+    pub fn release(&mut self) {
+        self.handle_input(OscillatorInput::Release);
+    }
+
+    /// Silence the oscillator now, skipping the release.
+    pub fn stop(&mut self) {
+        if let Some(gate) = &self.note_gate {
+            gate.release();
+        }
+        self.chord_voices.clear();
+        self.state = OscillatorState::Idle;
+    }
+
     pub fn set_adsr(&mut self, adsr: ADSR) {
         self.adsr = adsr;
     }
+
+    /// Replace the wave, e.g. to switch from a sine to a sawtooth while playing.
+    /// The accumulated phase is kept, so periodic waves continue from the same point in their cycle.
+    /// If both waves have constant phase offsets, the phase is shifted by their difference to stay aligned.
+    /// Noise doesn't use the phase, so switching to or from noise leaves it untouched.
+    /// Chord voices switch to the new wave too, keeping their own frequencies.
+    pub fn set_wave_function(&mut self, wave_function: WaveFunction) {
+        let old_offset = self.wave_function.phase_offset().and_then(Number::constant_value);
+        let new_offset = wave_function.phase_offset().and_then(Number::constant_value);
+        if let (Some(old_offset), Some(new_offset)) = (old_offset, new_offset) {
+            self.phase = (self.phase + old_offset - new_offset).rem_euclid(2.0 * PI);
+        }
+
+        for voice in &mut self.chord_voices {
+            let voice_frequency = voice.wave_function.frequency().and_then(Number::constant_value);
+            voice.wave_function = wave_function.clone();
+            if let Some(freq) = voice_frequency {
+                voice.wave_function.set_frequency(freq);
+            }
+        }
+
+        *self.wave_function = wave_function;
+    }
+
+    /// Change the phase offset of the wave without a click.
+    pub fn set_phase_offset(&mut self, phase: Number) {
+        self.wave_function.set_phase_offset(phase, &mut self.phase);
+    }
+
+    /// Insert an effect at `index` in the effect chain, shifting later effects back. Panics if `index` is past the end.
+    pub fn insert_effect(&mut self, index: usize, effect: Effect) {
+        self.effects.insert(index, effect);
+    }
+
+    /// Remove and return the effect at `index`, or `None` if there is none.
+    pub fn remove_effect(&mut self, index: usize) -> Option<Effect> {
+        (index < self.effects.len()).then(|| self.effects.remove(index))
+    }
+
+    pub fn effect_count(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub(crate) fn latency_samples(&self) -> usize {
+        chain_latency(&self.effects)
+    }
+
+    pub(crate) fn collect_errors(&self, errors: &mut Vec<CompositionError>) {
+        if let Some(frequency) = self.wave_function.frequency() {
+            check_number_range("oscillator frequency", frequency, 0.0..=f32::MAX, errors);
+        }
+        check_range("ADSR attack duration", self.adsr.attack_duration, 0.0..=f32::MAX, errors);
+        check_range("ADSR decay duration", self.adsr.decay_duration, 0.0..=f32::MAX, errors);
+        check_range("ADSR sustain amplitude multiplier", self.adsr.sustain_amplitude_multiplier, 0.0..=f32::MAX, errors);
+        check_range("ADSR release duration", self.adsr.release_duration, 0.0..=f32::MAX, errors);
+
+        for effect in &self.effects {
+            effect.collect_errors(None, errors);
+        }
+    }
 }
 
 impl Clone for Oscillator {
@@ -115,6 +326,12 @@ impl Clone for Oscillator {
             state: self.state.clone(),
             secs_since_start: self.secs_since_start,
             adsr: self.adsr.clone(),
+            chord_voices: self.chord_voices.clone(),
+            drift: self.drift.clone(),
+            soft_clip: self.soft_clip,
+            nyquist_policy: self.nyquist_policy,
+            note_gate: self.note_gate.clone(),
+            history: self.history.clone(),
         }
     }
 }
@@ -134,33 +351,60 @@ impl SoundTrait for Oscillator {
 
         self.index += 1;
         let dt = 1.0 / *SAMPLE_RATE as f32;
+        let wave_dt = self.drift.as_mut().map_or(dt, |drift| dt * drift.next_ratio(dt));
 
-        self.wave_function.next_value(&mut self.phase, dt)
+        self.wave_function.next_value_with_policy(&mut self.phase, wave_dt, self.nyquist_policy)
     }
 
     fn next_grain(&mut self) -> Grain {
         self.update_inputs();
 
         // get grain
-        let mut grain = [0.0; SAMPLES_PER_GRAIN];
-        for sample in &mut grain {
-            *sample = self.next_sample();
-        }
+        let dt = 1.0 / *SAMPLE_RATE as f32;
+        let mut grain = if let OscillatorState::Idle = &self.state {
+            [0.0; SAMPLES_PER_GRAIN]
+        } else {
+            self.index += SAMPLES_PER_GRAIN;
+            // drift is slow enough to change once per grain; scaling time scales every frequency on top of any modulation
+            let wave_dt = self.drift.as_mut().map_or(dt, |drift| dt * drift.next_ratio(SAMPLES_PER_GRAIN as f32 * dt));
+            let mut grain = self.wave_function.next_grain_with_policy(&mut self.phase, wave_dt, self.nyquist_policy);
+            for voice in &mut self.chord_voices {
+                let voice_grain = voice.wave_function.next_grain_with_policy(&mut voice.phase, wave_dt, self.nyquist_policy);
+                for (i, sample) in voice_grain.iter().enumerate() {
+                    grain[i] += sample;
+                }
+            }
+
+            // scale a chord by its number of tones, so it peaks no higher than a single note and can't clip
+            if !self.chord_voices.is_empty() {
+                let scale = 1.0 / (1 + self.chord_voices.len()) as f32;
+                for sample in &mut grain {
+                    *sample *= scale;
+                }
+            }
+
+            grain
+        };
+        self.secs_since_start += SAMPLES_PER_GRAIN as f32 * dt;
 
         // apply effects
         let mut oscillator_changes = Vec::new();
-        for effect in &mut self.effects {
-            let input = EffectInput {
-                grain,
-                time_since_start_of_beat: self.secs_since_start,
-            };
-            let output = effect.apply(input);
+        let context = EffectContext {
+            sample_rate: *SAMPLE_RATE,
+            secs_per_beat: None,
+            time_since_start: self.secs_since_start,
+            history: &self.history,
+        };
+        let dry = grain;
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            let output = profiling::indexed("effects", i, || effect.apply(grain, &context));
             grain = output.grain;
 
             for change in output.oscillator_changes {
                 oscillator_changes.push(change);
             }
         }
+        self.history.push_grain(&dry);
 
         for change in oscillator_changes {
             self.apply_change(change);
@@ -170,49 +414,33 @@ impl SoundTrait for Oscillator {
         match &self.state {
             OscillatorState::Idle => {},
             OscillatorState::Play { started_at } => {
-                // attack/decay/sustain
-                let secs_since_start_of_play = self.secs_since_start - started_at;
-
-                let decay_start = self.adsr.attack_duration;
-                let sustain_start = decay_start + self.adsr.decay_duration;
-
-                if secs_since_start_of_play < decay_start {
-                    // attack
-                    let attack_progress = secs_since_start_of_play / self.adsr.attack_duration;
-                    for sample in &mut grain {
-                        *sample *= attack_progress;
-                    }
-                } else if secs_since_start_of_play < sustain_start {
-                    // decay
-                    let decay_progress = (secs_since_start_of_play - decay_start) / self.adsr.decay_duration;
-                    let diff = 1.0 - self.adsr.sustain_amplitude_multiplier;
-                    let amplitude = 1.0 - diff * decay_progress;
-                    for sample in &mut grain {
-                        *sample *= amplitude;
-                    }
-                } else {
-                    // sustain
-                    for sample in &mut grain {
-                        *sample *= self.adsr.sustain_amplitude_multiplier;
-                    }
+                // attack/decay/sustain, sample by sample so short attacks ramp up instead of stepping once per grain
+                let grain_start = self.secs_since_start - SAMPLES_PER_GRAIN as f32 * dt - started_at;
+                for (i, sample) in grain.iter_mut().enumerate() {
+                    *sample *= self.adsr.held_amplitude(grain_start + (i + 1) as f32 * dt);
                 }
             },
             OscillatorState::Release { started_at } => {
-                // release
-                let secs_since_start_of_release = self.secs_since_start - started_at;
-                if secs_since_start_of_release > self.adsr.release_duration {
+                // release, sample by sample so a release ending within the grain ramps to zero instead of clicking off
+                let sustain = self.adsr.sustain_amplitude_multiplier;
+                let grain_start = self.secs_since_start - SAMPLES_PER_GRAIN as f32 * dt - started_at;
+                for (i, sample) in grain.iter_mut().enumerate() {
+                    let secs_since_start_of_release = grain_start + (i + 1) as f32 * dt;
+                    *sample *= self.adsr.release_amplitude(sustain, secs_since_start_of_release).unwrap_or(0.0);
+                }
+
+                if self.adsr.release_amplitude(sustain, self.secs_since_start - started_at).is_none() {
                     self.state = OscillatorState::Idle;
-                    grain = [0.0; SAMPLES_PER_GRAIN];
-                } else {
-                    let release_progress = secs_since_start_of_release / self.adsr.release_duration;
-                    let amplitude = self.adsr.sustain_amplitude_multiplier * (1.0 - release_progress);
-                    for sample in &mut grain {
-                        *sample *= amplitude;
-                    }
                 }
             },
         }
 
+        if self.soft_clip {
+            for sample in &mut grain {
+                *sample = sample.tanh();
+            }
+        }
+
         grain
     }
 
@@ -229,6 +457,12 @@ impl SoundTrait for Oscillator {
             state: self.state.clone(),
             secs_since_start: self.secs_since_start,
             adsr: self.adsr.clone(),
+            chord_voices: self.chord_voices.clone(),
+            drift: self.drift.clone(),
+            soft_clip: self.soft_clip,
+            nyquist_policy: self.nyquist_policy,
+            note_gate: self.note_gate.clone(),
+            history: self.history.clone(),
         })
     }
 
@@ -237,12 +471,18 @@ impl SoundTrait for Oscillator {
     }
 }
 
+#[derive(Clone)]
 pub struct OscillatorBuilder {
     pub wave_function: Option<WaveFunction>,
     // pub effects: Vec<Box<dyn EffectTrait>>,
     pub effects: Vec<Effect>,
     pub inputs: Option<OscillatorInputIterator>,
     pub adsr: Option<ADSR>,
+    pub drift: Option<Drift>,
+    pub soft_clip: bool,
+    pub nyquist_policy: NyquistPolicy,
+    pub note_gate: Option<NoteGate>,
+    pub history_samples: usize,
 }
 
 impl OscillatorBuilder {
@@ -252,6 +492,11 @@ impl OscillatorBuilder {
             effects: Vec::new(),
             inputs: None,
             adsr: None,
+            drift: None,
+            soft_clip: false,
+            nyquist_policy: NyquistPolicy::default(),
+            note_gate: None,
+            history_samples: HISTORY_SAMPLES,
         }
     }
 
@@ -275,6 +520,76 @@ impl OscillatorBuilder {
         self
     }
 
+    /// Add a subtle random pitch drift of up to `amount_cents`, wandering about `rate_hz` times a second.
+    /// Uses seed 0; see `drift_with_seed`.
+    pub fn drift(self, amount_cents: f32, rate_hz: f32) -> Self {
+        self.drift_with_seed(amount_cents, rate_hz, 0)
+    }
+
+    pub fn drift_with_seed(mut self, amount_cents: f32, rate_hz: f32, seed: u64) -> Self {
+        self.drift = Some(Drift::new(amount_cents, rate_hz, seed));
+        self
+    }
+
+    /// Soft-clip the oscillator's output with tanh, after its effects and envelope, so it always stays within ±1.
+    pub fn soft_clip(mut self, soft_clip: bool) -> Self {
+        self.soft_clip = soft_clip;
+        self
+    }
+
+    /// What happens when the frequency is modulated above Nyquist. Clamps it just below by default.
+    pub fn nyquist_policy(mut self, nyquist_policy: NyquistPolicy) -> Self {
+        self.nyquist_policy = nyquist_policy;
+        self
+    }
+
+    /// Tell `gate` about every press and release, so `Number::note_envelope`s made with it follow this oscillator's notes.
+    pub fn note_gate(mut self, gate: NoteGate) -> Self {
+        self.note_gate = Some(gate);
+        self
+    }
+
+    /// How many of the oscillator's recent dry samples to keep for its effects to look back on. See `History`.
+    pub fn history_samples(mut self, samples: usize) -> Self {
+        self.history_samples = samples;
+        self
+    }
+
+    /// Start playing as soon as the oscillator starts, and never release. Useful for drones.
+    pub fn auto_play(mut self) -> Self {
+        let inputs = OscillatorInputIteratorBuilder::new()
+            .input(OscillatorInputAtTime {
+                input: OscillatorInput::PressSame,
+                time: 0.0,
+            })
+            .build();
+
+        self.inputs = Some(inputs);
+        self
+    }
+
+    /// Build one auto-playing oscillator per note, each a copy of this builder's settings with its wave set to that note.
+    /// Useful for static chords and pads: add them all to a `Composition`. Panics if a note name is invalid.
+    /// Any drift is copied too, so give the voices different `drift_with_seed`s by hand if they should drift apart.
+    /// Each voice ends with a `Volume` of 1 / the number of notes, so the chord peaks no higher than a single note.
+    pub fn spawn_chord(self, notes: &[&str]) -> Vec<Oscillator> {
+        let volume = 1.0 / notes.len() as f32;
+
+        notes
+            .iter()
+            .map(|note_name| {
+                let mut wave_function = self.wave_function.clone().unwrap();
+                wave_function.set_frequency(note(note_name));
+
+                self.clone()
+                    .wave_function(wave_function)
+                    .effect(Effect::Volume(Volume(Number::number(volume))))
+                    .auto_play()
+                    .build()
+            })
+            .collect()
+    }
+
     pub fn build(self) -> Oscillator {
         let adsr = self.adsr.unwrap_or(ADSR::new(0.1, 0.1, 1.0, 0.1));
 
@@ -287,6 +602,124 @@ impl OscillatorBuilder {
             state: OscillatorState::Idle,
             secs_since_start: 0.0,
             adsr,
+            chord_voices: Vec::new(),
+            drift: self.drift,
+            soft_clip: self.soft_clip,
+            nyquist_policy: self.nyquist_policy,
+            note_gate: self.note_gate,
+            history: History::new(self.history_samples),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The largest jump between neighbouring samples of the grains, including across grain boundaries.
+    fn largest_step(grains: &[Grain]) -> f32 {
+        let samples: Vec<f32> = grains.iter().flatten().copied().collect();
+        samples.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn changing_frequency_and_phase_mid_note_is_continuous() {
+        crate::player::fix_sample_rate(48000);
+        let mut oscillator = Oscillator::from_spec("sine A4 adsr(0, 0, 1, 0.1)").unwrap();
+        let mut grains = vec![oscillator.next_grain(), oscillator.next_grain()];
+
+        oscillator.apply_change(OscillatorChange::Frequency(660.0));
+        grains.push(oscillator.next_grain());
+        oscillator.set_phase_offset(Number::number(1.0));
+        grains.push(oscillator.next_grain());
+        oscillator.set_phase_offset(Number::number(-2.0));
+        grains.push(oscillator.next_grain());
+
+        // a sine can't step further between samples than its steepest slope
+        let steepest = 2.0 * PI * 660.0 / 48000.0;
+        assert!(largest_step(&grains) <= 1.01 * steepest, "stepped by {}", largest_step(&grains));
+    }
+
+    #[test]
+    fn short_release_ramps_to_silence_without_a_click() {
+        crate::player::fix_sample_rate(48000);
+        let mut oscillator = Oscillator::from_spec("sine A4 adsr(0, 0, 1, 0.001)").unwrap();
+        let mut grains = vec![oscillator.next_grain(), oscillator.next_grain()];
+
+        oscillator.release();
+        while !matches!(oscillator.state, OscillatorState::Idle) {
+            grains.push(oscillator.next_grain());
+        }
+        let released = grains.last().unwrap();
+        assert_eq!(released[SAMPLES_PER_GRAIN - 1], 0.0);
+        grains.push(oscillator.next_grain());
+
+        // the sine's own slope, plus the 48-sample ramp down from full level
+        let steepest = 2.0 * PI * 440.0 / 48000.0 + 1.0 / 48.0;
+        assert!(largest_step(&grains) <= 1.01 * steepest, "stepped by {}", largest_step(&grains));
+    }
+
+    #[test]
+    fn zero_attack_ramps_up_without_a_click() {
+        crate::player::fix_sample_rate(48000);
+        // a cosine starts at full level, so an attack that jumped straight to it would click
+        let mut oscillator = OscillatorBuilder::new()
+            .wave_function(WaveFunction::Sine {
+                frequency: Number::number(440.0),
+                amplitude: Number::number(1.0),
+                phase: Number::number(PI / 2.0),
+            })
+            .adsr(ADSR::new(0.0, 0.0, 1.0, 0.1))
+            .auto_play()
+            .build();
+        let mut grains = vec![[0.0; SAMPLES_PER_GRAIN]];
+        grains.extend((0..4).map(|_| oscillator.next_grain()));
+
+        assert!(grains.iter().flatten().all(|sample| sample.is_finite()));
+        let steepest = 2.0 * PI * 440.0 / 48000.0 + 1.0 / MIN_RAMP_SAMPLES;
+        assert!(largest_step(&grains) <= 1.01 * steepest, "stepped by {}", largest_step(&grains));
+    }
+
+    #[test]
+    fn zero_release_ramps_down_without_a_click() {
+        crate::player::fix_sample_rate(48000);
+        let mut oscillator = Oscillator::from_spec("sine A4 adsr(0, 0, 1, 0)").unwrap();
+        let mut grains = vec![oscillator.next_grain(), oscillator.next_grain()];
+
+        oscillator.release();
+        grains.extend((0..3).map(|_| oscillator.next_grain()));
+        assert!(matches!(oscillator.state, OscillatorState::Idle));
+
+        assert!(grains.iter().flatten().all(|sample| sample.is_finite()));
+        let steepest = 2.0 * PI * 440.0 / 48000.0 + 1.0 / MIN_RAMP_SAMPLES;
+        assert!(largest_step(&grains) <= 1.01 * steepest, "stepped by {}", largest_step(&grains));
+    }
+
+    #[test]
+    fn next_sample_follows_the_nyquist_policy() {
+        crate::player::fix_sample_rate(48000);
+        let mut oscillator = OscillatorBuilder::new()
+            .wave_function(WaveFunction::Sine {
+                frequency: Number::number(30000.0),
+                amplitude: Number::number(1.0),
+                phase: Number::number(0.0),
+            })
+            .nyquist_policy(NyquistPolicy::Mute)
+            .auto_play()
+            .build();
+        oscillator.next_grain();
+
+        assert!((0..SAMPLES_PER_GRAIN).all(|_| oscillator.next_sample() == 0.0));
+    }
+
+    #[test]
+    fn chords_are_no_louder_than_a_single_note() {
+        crate::player::fix_sample_rate(48000);
+        let mut oscillator = Oscillator::from_spec("saw C3 adsr(0, 0, 1, 0.1)").unwrap();
+        oscillator.handle_input(OscillatorInput::PressChord(chord(&["C3", "E3", "G3"])));
+
+        let samples: Vec<f32> = (0..100).flat_map(|_| oscillator.next_grain()).collect();
+        let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.5 && peak <= 1.0, "peak of {peak}");
+    }
+}
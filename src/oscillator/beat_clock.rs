@@ -0,0 +1,71 @@
+use std::sync::{atomic::{AtomicU32, AtomicU64, Ordering}, Arc};
+
+/// Shares a composition's beat position with the `Number`s synced to it.
+/// Give a clone to `CompositionBuilder::beat_clock` and another to each synced number. Clones all share the same position.
+/// Until a composition with a tempo publishes to it, synced numbers run freely.
+#[derive(Clone, Debug, Default)]
+pub struct BeatClock {
+    grains: Arc<AtomicU64>, // counts published grains, so a number can tell when a new one starts
+    grain_start_beats: Arc<AtomicU32>, // f32 bits
+    beats_per_sample: Arc<AtomicU32>, // f32 bits
+}
+
+impl BeatClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish the beat position at the start of the grain about to be rendered, and the tempo through it.
+    pub(crate) fn publish(&self, grain_start_beats: f32, beats_per_sample: f32) {
+        self.grain_start_beats.store(grain_start_beats.to_bits(), Ordering::Relaxed);
+        self.beats_per_sample.store(beats_per_sample.to_bits(), Ordering::Relaxed);
+        self.grains.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Tracks a `BeatClock` sample by sample and reports when it crosses into a new division of the beat.
+#[derive(Clone, Debug)]
+pub struct Retrigger {
+    clock: BeatClock,
+    division_beats: f32,
+    grains_seen: u64,
+    samples_into_grain: usize,
+    division: Option<i64>, // the division the clock was last in
+}
+
+impl Retrigger {
+    pub fn new(clock: BeatClock, division_beats: f32) -> Self {
+        assert!(division_beats > 0.0, "division must be positive");
+
+        Self {
+            clock,
+            division_beats,
+            grains_seen: 0,
+            samples_into_grain: 0,
+            division: None,
+        }
+    }
+
+    /// Advance by a sample, returning whether a new division has started, including the first one heard.
+    pub fn advance(&mut self) -> bool {
+        let grains = self.clock.grains.load(Ordering::Relaxed);
+        if grains == 0 {
+            return false;
+        }
+        if grains != self.grains_seen {
+            self.grains_seen = grains;
+            self.samples_into_grain = 0;
+        }
+
+        let start = f32::from_bits(self.clock.grain_start_beats.load(Ordering::Relaxed));
+        let per_sample = f32::from_bits(self.clock.beats_per_sample.load(Ordering::Relaxed));
+        let beats = start + per_sample * self.samples_into_grain as f32;
+        self.samples_into_grain += 1;
+
+        let division = (beats / self.division_beats).floor() as i64;
+        let started = self.division != Some(division);
+        self.division = Some(division);
+
+        started
+    }
+}
@@ -0,0 +1,52 @@
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::f32::consts::PI;
+
+/// Slow, aperiodic pitch instability, like an analog oscillator warming up.
+/// Every `1 / rate_hz` seconds a new random offset is picked, and the pitch glides smoothly towards it.
+#[derive(Clone, Debug)]
+pub struct Drift {
+    amount_cents: f32,
+    rate_hz: f32,
+    rng: SmallRng,
+    previous_cents: f32,
+    target_cents: f32,
+    progress: f32, // from 0 to 1, between the previous and target offsets
+}
+
+impl Drift {
+    /// The same seed always drifts the same way. Give unison voices different seeds so they drift apart.
+    pub fn new(amount_cents: f32, rate_hz: f32, seed: u64) -> Self {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let target_cents = rng.random_range(-1.0..=1.0) * amount_cents;
+
+        Self {
+            amount_cents,
+            rate_hz,
+            rng,
+            previous_cents: 0.0,
+            target_cents,
+            progress: 0.0,
+        }
+    }
+
+    /// Advance by `dt` seconds, returning the ratio to multiply the frequency by.
+    pub fn next_ratio(&mut self, dt: f32) -> f32 {
+        self.progress += self.rate_hz * dt;
+        while self.progress >= 1.0 {
+            self.progress -= 1.0;
+            self.previous_cents = self.target_cents;
+            self.target_cents = self.rng.random_range(-1.0..=1.0) * self.amount_cents;
+        }
+
+        self.current_ratio()
+    }
+
+    /// The ratio the frequency is currently multiplied by, without advancing.
+    pub fn current_ratio(&self) -> f32 {
+        // ease in and out so the pitch never changes direction abruptly
+        let eased = (1.0 - (self.progress * PI).cos()) / 2.0;
+        let cents = self.previous_cents + (self.target_cents - self.previous_cents) * eased;
+
+        2.0f32.powf(cents / 1200.0)
+    }
+}
@@ -1,24 +1,54 @@
+use super::{NoteParseError, try_note};
+
 /// An input to an oscillator. Like a simplified form of MIDI.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum OscillatorInput {
     Press(f32), // frequency in Hz
+    PressChord(Vec<f32>), // frequencies in Hz, one voice each, released together
     PressSame, // press the same frequency as the last input
     Release,
 }
 
 /// An input to be sent to an oscillator at a given time.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct OscillatorInputAtTime {
     pub input: OscillatorInput,
     pub time: f32, // in seconds since the start of the oscillator
 }
 
+impl OscillatorInputAtTime {
+    /// Press the note with the given name, like "C#3", at the given time.
+    pub fn press(note_name: &str, time: f32) -> Result<Self, NoteParseError> {
+        Ok(Self {
+            input: OscillatorInput::Press(try_note(note_name)?),
+            time,
+        })
+    }
+
+    /// Press the given frequency in Hz at the given time.
+    pub fn press_frequency(frequency: f32, time: f32) -> Self {
+        Self { input: OscillatorInput::Press(frequency), time }
+    }
+
+    /// Press several notes at once, like `["C3", "E3", "G3"]`, at the given time.
+    pub fn press_chord(note_names: &[&str], time: f32) -> Result<Self, NoteParseError> {
+        let frequencies = note_names.iter().map(|note_name| try_note(note_name)).collect::<Result<_, _>>()?;
+
+        Ok(Self { input: OscillatorInput::PressChord(frequencies), time })
+    }
+
+    pub fn release(time: f32) -> Self {
+        Self { input: OscillatorInput::Release, time }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct OscillatorInputIterator {
     inputs: Vec<OscillatorInputAtTime>,
     index: usize,
     total_duration: f32,
     repeat_delay: Option<f32>, // in seconds
+    repeat_overlap: f32, // in seconds
 }
 
 impl OscillatorInputIterator {
@@ -30,13 +60,14 @@ impl OscillatorInputIterator {
             index: 0,
             total_duration,
             repeat_delay,
+            repeat_overlap: 0.0,
         }
     }
 
     fn repeat_inputs(&mut self) {
         if let Some(delay) = self.repeat_delay {
             for OscillatorInputAtTime { time, .. } in self.inputs.iter_mut() {
-                *time += self.total_duration + delay;
+                *time += self.total_duration + delay - self.repeat_overlap;
             }
 
             self.index = 0;
@@ -48,7 +79,7 @@ impl OscillatorInputIterator {
             return None;
         }
 
-        let index_input = self.inputs[self.index];
+        let index_input = self.inputs[self.index].clone();
         let next_input = if secs_since_start >= index_input.time {
             self.index += 1;
             if self.index >= self.inputs.len() {
@@ -67,11 +98,12 @@ impl OscillatorInputIterator {
 pub struct OscillatorInputIteratorBuilder {
     inputs: Vec<OscillatorInputAtTime>,
     repeat_delay: Option<f32>,
+    repeat_overlap: f32,
 }
 
 impl OscillatorInputIteratorBuilder {
     pub fn new() -> Self {
-        Self { inputs: vec![], repeat_delay: None }
+        Self { inputs: vec![], repeat_delay: None, repeat_overlap: 0.0 }
     }
 
     pub fn input(mut self, input: OscillatorInputAtTime) -> Self {
@@ -84,7 +116,18 @@ impl OscillatorInputIteratorBuilder {
         self
     }
 
+    /// Start each repeat `overlap` seconds earlier, so the first note of the next loop begins while the last note of
+    /// the previous one is still releasing instead of after a gap. Inputs are never sent out of order, so an overlap
+    /// longer than the repeat delay just starts the next loop straight after the last input.
+    pub fn loop_overlap(mut self, overlap: f32) -> Self {
+        self.repeat_overlap = overlap;
+        self
+    }
+
     pub fn build(self) -> OscillatorInputIterator {
-        OscillatorInputIterator::new(self.inputs, self.repeat_delay)
+        let mut iterator = OscillatorInputIterator::new(self.inputs, self.repeat_delay);
+        iterator.repeat_overlap = self.repeat_overlap;
+
+        iterator
     }
 }
@@ -1,5 +1,22 @@
-use std::f32::consts::PI;
-use crate::player::SAMPLE_RATE;
+use std::{cell::RefCell, f32::consts::PI, fmt::Debug, sync::Arc};
+use super::{beat_clock::{BeatClock, Retrigger}, note_envelope::{NoteEnvelope, NoteGate}, ADSR};
+use crate::{player::SAMPLE_RATE, sound::{Grain, SoundTrait, SAMPLES_PER_GRAIN}};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+thread_local! {
+    // noise is generated on whichever thread renders it, so each thread has its own generator
+    static NOISE_RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_os_rng());
+}
+
+/// Reseed the noise generator on the current thread, so the noise it renders from now on is reproducible.
+pub(crate) fn seed_noise(seed: u64) {
+    NOISE_RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+/// A uniformly random sample in [-1, 1].
+fn noise_sample() -> f32 {
+    NOISE_RNG.with(|rng| rng.borrow_mut().random_range(-1.0..=1.0))
+}
 
 #[derive(Clone, Debug)]
 pub struct LFO {
@@ -12,6 +29,11 @@ impl LFO {
         let dt = 1.0 / *SAMPLE_RATE as f32;
         self.wave_function.next_value(&mut self.phase, dt)
     }
+
+    /// Restart the wave from the beginning of its cycle.
+    pub fn reset_phase(&mut self) {
+        self.phase = 0.0;
+    }
 }
 
 pub struct LFOBuilder {
@@ -45,6 +67,68 @@ impl LFOBuilder {
     }
 }
 
+/// Tracks the smoothed absolute level of another sound.
+pub struct EnvelopeFollower {
+    source: Box<dyn SoundTrait>,
+    attack: f32, // in seconds
+    release: f32, // in seconds
+    envelope: f32,
+    grain: Grain, // the source is pulled a grain at a time
+    index: usize,
+}
+
+impl EnvelopeFollower {
+    pub fn new(source: Box<dyn SoundTrait>, attack: f32, release: f32) -> Self {
+        Self {
+            source,
+            attack,
+            release,
+            envelope: 0.0,
+            grain: [0.0; SAMPLES_PER_GRAIN],
+            index: SAMPLES_PER_GRAIN,
+        }
+    }
+
+    pub fn next_value(&mut self) -> f32 {
+        if self.index == SAMPLES_PER_GRAIN {
+            self.grain = self.source.next_grain();
+            self.index = 0;
+        }
+        let level = self.grain[self.index].abs();
+        self.index += 1;
+
+        // one-pole smoothing, reaching about 63% of a step in `attack` or `release` seconds
+        let time = if level > self.envelope { self.attack } else { self.release };
+        let coefficient = if time > 0.0 { (-1.0 / (time * *SAMPLE_RATE as f32)).exp() } else { 0.0 };
+        self.envelope = level + coefficient * (self.envelope - level);
+
+        self.envelope
+    }
+}
+
+impl Clone for EnvelopeFollower {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone_box(),
+            attack: self.attack,
+            release: self.release,
+            envelope: self.envelope,
+            grain: self.grain,
+            index: self.index,
+        }
+    }
+}
+
+impl Debug for EnvelopeFollower {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnvelopeFollower")
+            .field("attack", &self.attack)
+            .field("release", &self.release)
+            .field("envelope", &self.envelope)
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub enum Number {
     Number {
@@ -57,6 +141,68 @@ pub enum Number {
         plus: f32,
         mul: f32,
     },
+    Slew {
+        number: Box<Number>,
+        max_change_per_sec: f32,
+        current: Option<f32>,
+        plus: f32,
+        mul: f32,
+    },
+    Follow {
+        follower: Box<EnvelopeFollower>,
+        plus: f32,
+        mul: f32,
+    },
+    Crossfade {
+        a: Box<Number>,
+        b: Box<Number>,
+        position: Box<Number>,
+        plus: f32,
+        mul: f32,
+    },
+    Curve {
+        number: Box<Number>,
+        curve: Curve,
+        plus: f32,
+        mul: f32,
+    },
+    NoteEnvelope {
+        envelope: Box<NoteEnvelope>,
+        plus: f32,
+        mul: f32,
+    },
+    Retriggered {
+        oscillator: LFO,
+        retrigger: Box<Retrigger>,
+        plus: f32,
+        mul: f32,
+    },
+}
+
+/// A nonlinear reshaping of a number, e.g. so modulation sounds even across a parameter heard logarithmically.
+#[derive(Clone, Copy, Debug)]
+pub enum Curve {
+    /// `x` to the power of the exponent, keeping the sign of `x` so negative values don't become NaN.
+    Power(f32),
+    /// The base to the power of `x`, mapping [0, 1] to [1, base].
+    Exponential(f32),
+}
+
+impl Curve {
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Curve::Power(exponent) => x.signum() * x.abs().powf(exponent),
+            Curve::Exponential(base) => base.powf(x),
+        }
+    }
+}
+
+/// Move `current` towards `target` by at most `max_change`.
+pub(crate) fn slew_towards(current: f32, target: f32, max_change: f32) -> f32 {
+    let diff = target - current;
+    let change = diff.clamp(-max_change, max_change);
+
+    current + change
 }
 
 impl Clone for Number {
@@ -72,6 +218,42 @@ impl Clone for Number {
                 plus: *plus,
                 mul: *mul,
             },
+            Number::Slew { number, max_change_per_sec, current, plus, mul } => Number::Slew {
+                number: number.clone(),
+                max_change_per_sec: *max_change_per_sec,
+                current: *current,
+                plus: *plus,
+                mul: *mul,
+            },
+            Number::Follow { follower, plus, mul } => Number::Follow {
+                follower: follower.clone(),
+                plus: *plus,
+                mul: *mul,
+            },
+            Number::Crossfade { a, b, position, plus, mul } => Number::Crossfade {
+                a: a.clone(),
+                b: b.clone(),
+                position: position.clone(),
+                plus: *plus,
+                mul: *mul,
+            },
+            Number::Curve { number, curve, plus, mul } => Number::Curve {
+                number: number.clone(),
+                curve: *curve,
+                plus: *plus,
+                mul: *mul,
+            },
+            Number::NoteEnvelope { envelope, plus, mul } => Number::NoteEnvelope {
+                envelope: envelope.clone(),
+                plus: *plus,
+                mul: *mul,
+            },
+            Number::Retriggered { oscillator, retrigger, plus, mul } => Number::Retriggered {
+                oscillator: oscillator.clone(),
+                retrigger: retrigger.clone(),
+                plus: *plus,
+                mul: *mul,
+            },
         }
     }
 }
@@ -111,12 +293,153 @@ impl Number {
         Number::oscillator(oscillator).plus_f32(middle)
     }
 
+    /// Like `sine_around`, but restarting its cycle every `division_beats` beats of the composition publishing to `clock`
+    /// (see `CompositionBuilder::beat_clock`), so rhythmic modulation stays locked to the beat however long the piece runs.
+    /// For example, a division of 4 in 4/4 restarts on every bar.
+    pub fn sine_synced_retrig(middle: f32, plus_or_minus: f32, frequency: f32, division_beats: f32, clock: BeatClock) -> Self {
+        let oscillator = LFOBuilder::new()
+            .wave_function(WaveFunction::Sine {
+                frequency: Number::number(frequency),
+                amplitude: Number::number(plus_or_minus),
+                phase: Number::number(0.0),
+            })
+            .build();
+
+        Number::Retriggered {
+            oscillator,
+            retrigger: Box::new(Retrigger::new(clock, division_beats)),
+            plus: middle,
+            mul: 1.0,
+        }
+    }
+
+    /// Limit how fast the number can change, to at most `rate_per_sec` per second.
+    /// Useful to avoid zipper noise when modulating with stepped sources.
+    pub fn slew(self, rate_per_sec: f32) -> Self {
+        Number::Slew {
+            number: Box::new(self),
+            max_change_per_sec: rate_per_sec,
+            current: None,
+            plus: 0.0,
+            mul: 1.0,
+        }
+    }
+
+    /// Follow the loudness of another sound, e.g. to duck one sound whenever another plays.
+    /// `attack` and `release` are how quickly the level rises and falls, in seconds.
+    /// Use `mul_f32` and `plus_f32` to map the level onto the range of the parameter being modulated.
+    pub fn follow(source: Box<dyn SoundTrait>, attack: f32, release: f32) -> Self {
+        Number::Follow {
+            follower: Box::new(EnvelopeFollower::new(source, attack, release)),
+            plus: 0.0,
+            mul: 1.0,
+        }
+    }
+
+    /// Blend between two numbers, from all `a` at a position of 0 to all `b` at 1.
+    /// The position is clamped to [0, 1]. Both numbers keep running whatever the position.
+    pub fn crossfade(a: Number, b: Number, position: Number) -> Self {
+        Number::Crossfade {
+            a: Box::new(a),
+            b: Box::new(b),
+            position: Box::new(position),
+            plus: 0.0,
+            mul: 1.0,
+        }
+    }
+
+    /// Raise the number to `exponent`, e.g. 2 to spend more of a [0, 1] range near 0.
+    pub fn pow(self, exponent: f32) -> Self {
+        self.curve(Curve::Power(exponent))
+    }
+
+    /// Raise `base` to the number, so a [0, 1] number maps to [1, base] exponentially.
+    /// For example, `lfo.exp_map(100.0).mul_f32(200.0)` sweeps a cutoff from 200 Hz to 20 kHz evenly by ear.
+    pub fn exp_map(self, base: f32) -> Self {
+        self.curve(Curve::Exponential(base))
+    }
+
+    /// An envelope that follows the notes of an oscillator, e.g. so a filter opens with each note.
+    /// It restarts on every press and releases on release of the oscillator built with `gate`
+    /// (see `OscillatorBuilder::note_gate`), going from 0 up to 1 and back.
+    pub fn note_envelope(adsr: ADSR, gate: NoteGate) -> Self {
+        Number::NoteEnvelope {
+            envelope: Box::new(NoteEnvelope::new(adsr, gate)),
+            plus: 0.0,
+            mul: 1.0,
+        }
+    }
+
+    pub fn curve(self, curve: Curve) -> Self {
+        Number::Curve {
+            number: Box::new(self),
+            curve,
+            plus: 0.0,
+            mul: 1.0,
+        }
+    }
+
+    /// The value of the number if it never changes, otherwise `None`.
+    pub fn constant_value(&self) -> Option<f32> {
+        match self {
+            Number::Number { value, plus, mul } => Some(*mul * *value + *plus),
+            Number::Crossfade { a, b, position, plus, mul } => {
+                let (a, b) = (a.constant_value()?, b.constant_value()?);
+                let position = position.constant_value()?.clamp(0.0, 1.0);
+
+                Some(*mul * ((1.0 - position) * a + position * b) + *plus)
+            },
+            Number::Curve { number, curve, plus, mul } => Some(*mul * curve.apply(number.constant_value()?) + *plus),
+            Number::Oscillator { .. } | Number::Slew { .. } | Number::Follow { .. } | Number::NoteEnvelope { .. } | Number::Retriggered { .. } => None,
+        }
+    }
+
     pub fn next_value(&mut self) -> f32 {
         match self {
             Number::Number { value, plus, mul } => *mul * *value + *plus,
             Number::Oscillator { oscillator, plus, mul } => {
                 let value = oscillator.next_value();
 
+                *mul * value + *plus
+            },
+            Number::Slew { number, max_change_per_sec, current, plus, mul } => {
+                let target = number.next_value();
+                let value = match current {
+                    Some(current) => slew_towards(*current, target, *max_change_per_sec / *SAMPLE_RATE as f32),
+                    None => target,
+                };
+                *current = Some(value);
+
+                *mul * value + *plus
+            },
+            Number::Follow { follower, plus, mul } => {
+                let value = follower.next_value();
+
+                *mul * value + *plus
+            },
+            Number::Crossfade { a, b, position, plus, mul } => {
+                let (a, b) = (a.next_value(), b.next_value());
+                let position = position.next_value().clamp(0.0, 1.0);
+                let value = (1.0 - position) * a + position * b;
+
+                *mul * value + *plus
+            },
+            Number::Curve { number, curve, plus, mul } => {
+                let value = curve.apply(number.next_value());
+
+                *mul * value + *plus
+            },
+            Number::NoteEnvelope { envelope, plus, mul } => {
+                let value = envelope.next_value();
+
+                *mul * value + *plus
+            },
+            Number::Retriggered { oscillator, retrigger, plus, mul } => {
+                if retrigger.advance() {
+                    oscillator.reset_phase();
+                }
+                let value = oscillator.next_value();
+
                 *mul * value + *plus
             },
         }
@@ -134,6 +457,42 @@ impl Number {
                 plus: plus + rhs,
                 mul: mul.clone(),
             },
+            Number::Slew { number, max_change_per_sec, current, plus, mul } => Number::Slew {
+                number,
+                max_change_per_sec,
+                current,
+                plus: plus + rhs,
+                mul,
+            },
+            Number::Follow { follower, plus, mul } => Number::Follow {
+                follower,
+                plus: plus + rhs,
+                mul,
+            },
+            Number::Crossfade { a, b, position, plus, mul } => Number::Crossfade {
+                a,
+                b,
+                position,
+                plus: plus + rhs,
+                mul,
+            },
+            Number::Curve { number, curve, plus, mul } => Number::Curve {
+                number,
+                curve,
+                plus: plus + rhs,
+                mul,
+            },
+            Number::NoteEnvelope { envelope, plus, mul } => Number::NoteEnvelope {
+                envelope,
+                plus: plus + rhs,
+                mul,
+            },
+            Number::Retriggered { oscillator, retrigger, plus, mul } => Number::Retriggered {
+                oscillator,
+                retrigger,
+                plus: plus + rhs,
+                mul,
+            },
         }
     }
 
@@ -149,10 +508,58 @@ impl Number {
                 plus: plus,
                 mul: mul * rhs,
             },
+            Number::Slew { number, max_change_per_sec, current, plus, mul } => Number::Slew {
+                number,
+                max_change_per_sec,
+                current,
+                plus,
+                mul: mul * rhs,
+            },
+            Number::Follow { follower, plus, mul } => Number::Follow {
+                follower,
+                plus,
+                mul: mul * rhs,
+            },
+            Number::Crossfade { a, b, position, plus, mul } => Number::Crossfade {
+                a,
+                b,
+                position,
+                plus,
+                mul: mul * rhs,
+            },
+            Number::Curve { number, curve, plus, mul } => Number::Curve {
+                number,
+                curve,
+                plus,
+                mul: mul * rhs,
+            },
+            Number::NoteEnvelope { envelope, plus, mul } => Number::NoteEnvelope {
+                envelope,
+                plus,
+                mul: mul * rhs,
+            },
+            Number::Retriggered { oscillator, retrigger, plus, mul } => Number::Retriggered {
+                oscillator,
+                retrigger,
+                plus,
+                mul: mul * rhs,
+            },
         }
     }
 }
 
+/// A user-provided wave shape, taking the normalized phase in [0, 1) and returning a sample.
+#[derive(Clone)]
+pub struct CustomWave(pub Arc<dyn Fn(f32) -> f32 + Send + Sync>);
+
+impl Debug for CustomWave {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Custom")
+    }
+}
+
+/// The shape of a wave. This is the only wave implementation, shared by audible `Oscillator`s and by `LFO`s,
+/// so the band-limiting on `Square`, `Triangle` and `Sawtooth` applies to both.
 #[derive(Clone, Debug)]
 pub enum WaveFunction {
     Sine {
@@ -165,6 +572,14 @@ pub enum WaveFunction {
         amplitude: Number,
         phase: Number,
     },
+    /// A square wave that is high for `duty_cycle` of each cycle, e.g. modulated with `Number::sine_around` for PWM.
+    /// The duty cycle is clamped to [`MIN_DUTY_CYCLE`, 1 - `MIN_DUTY_CYCLE`], so the wave never collapses into silence or DC.
+    Pulse {
+        frequency: Number,
+        amplitude: Number,
+        phase: Number,
+        duty_cycle: Number,
+    },
     Triangle {
         frequency: Number,
         amplitude: Number,
@@ -183,6 +598,74 @@ pub enum WaveFunction {
         generators: Vec<f32>,
         call_count: usize,
     },
+    /// White noise tilted by `color`, from -1 (blue, brighter) through 0 (white) to 1 (brown, darker).
+    /// Positive colors low-pass the noise with a one-pole filter and negative ones take a weighted first difference,
+    /// each normalized to keep the level roughly constant. `filtered` and `previous_white` are the filter state.
+    ColoredNoise {
+        amplitude: Number,
+        color: Number,
+        filtered: f32,
+        previous_white: f32,
+    },
+    Custom {
+        function: CustomWave,
+        frequency: Number,
+        amplitude: Number,
+    },
+    /// Crossfades between two waves, from all `a` at a mix of 0 to all `b` at a mix of 1.
+    /// Both waves are played at the morph's frequency and share its phase, so they stay aligned.
+    Morph {
+        a: Box<WaveFunction>,
+        b: Box<WaveFunction>,
+        mix: Number,
+        frequency: Number,
+    },
+    /// Two sines `detune_cents` apart, centred on `frequency`, for a gently chorused shimmer.
+    /// The lower partial follows the shared phase and the upper one keeps its own in `upper_phase`.
+    SinePair {
+        frequency: Number,
+        detune_cents: f32,
+        amplitude: Number,
+        upper_phase: f32,
+    },
+    /// Several detuned sawtooths, modelled on the Roland JP-8000 supersaw.
+    /// `detune` and `mix` go from 0 to 1, and `phases` holds the normalized phase of each side voice.
+    Supersaw {
+        frequency: Number,
+        detune: Number,
+        mix: Number,
+        phases: Vec<f32>,
+    },
+}
+
+/// The narrowest pulse `WaveFunction::Pulse` plays, as a fraction of a cycle.
+pub const MIN_DUTY_CYCLE: f32 = 0.01;
+
+/// What a wave does when its frequency is modulated past the Nyquist frequency (half the sample rate),
+/// where it would otherwise alias into harsh, unrelated tones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NyquistPolicy {
+    /// Hold the frequency just below Nyquist, so the wave keeps sounding at the highest pitch it can.
+    #[default]
+    Clamp,
+    /// Silence the wave while its frequency is too high. Its phase keeps moving at the clamped frequency.
+    Mute,
+    /// Play whatever frequency is asked for, aliasing and all.
+    Allow,
+}
+
+impl NyquistPolicy {
+    /// The frequency to play and a gain to apply, for a wanted frequency and time step.
+    fn limit(self, freq: f32, dt: f32) -> (f32, f32) {
+        // stay a little under Nyquist, where PolyBLEP has room to work
+        let max_freq = 0.45 / dt;
+        let clamped = freq.clamp(-max_freq, max_freq);
+        match self {
+            NyquistPolicy::Clamp => (clamped, 1.0),
+            NyquistPolicy::Mute => (clamped, if clamped == freq { 1.0 } else { 0.0 }),
+            NyquistPolicy::Allow => (freq, 1.0),
+        }
+    }
 }
 
 fn poly_blep(phase: f32, phase_increment: f32) -> f32 {
@@ -199,7 +682,61 @@ fn poly_blep(phase: f32, phase_increment: f32) -> f32 {
     }
 }
 
+/// The integral of `poly_blep`, for smoothing corners (slope discontinuities) rather than steps.
+fn poly_blamp(phase: f32, phase_increment: f32) -> f32 {
+    if phase < phase_increment {
+        let t = phase / phase_increment - 1.0;
+
+        -t * t * t / 3.0 // -t^3 / 3
+    } else if phase > 1.0 - phase_increment {
+        let t = (phase - 1.0) / phase_increment + 1.0;
+
+        t * t * t / 3.0 // t^3 / 3
+    } else {
+        0.0
+    }
+}
+
+/// A sawtooth at a normalized phase in [0, 1), with its reset smoothed.
+fn band_limited_sawtooth(normalized_phase: f32, phase_increment: f32) -> f32 {
+    2.0 * normalized_phase - 1.0 - poly_blep(normalized_phase, phase_increment)
+}
+
+/// How far the outermost supersaw voices are detuned, as a fraction of the frequency, for a `detune` from 0 to 1.
+/// This is the curve measured from the JP-8000, which stays subtle for most of the range and rises steeply at the top.
+fn supersaw_detune_curve(detune: f32) -> f32 {
+    // the coefficients cancel heavily, so evaluate in f64
+    let coefficients: [f64; 12] = [
+        10028.7312891634, -50818.8652045924, 111363.4808729368, -138150.6761080548, 106649.6679158292, -53046.9642751875,
+        17019.9518580080, -3425.0836591318, 404.2703938388, -24.1878824391, 0.6717417634, 0.0030115596,
+    ];
+
+    // horner's method, from the x^11 coefficient down
+    let curve = coefficients.iter().fold(0.0, |total, coefficient| total * detune as f64 + coefficient);
+
+    curve as f32 * 0.11
+}
+
 impl WaveFunction {
+    /// A supersaw with `voices` sawtooths, including the centre one. `detune` spreads the side voices apart
+    /// and `mix` fades them in against the centre voice, both from 0 to 1.
+    pub fn supersaw(frequency: Number, detune: Number, mix: Number, voices: usize) -> Self {
+        assert!(voices >= 1, "a supersaw needs at least one voice");
+
+        // start the side voices at spread-out phases so they don't all reset together
+        let phases = (1..voices).map(|i| (i as f32 * 0.618034).fract()).collect();
+
+        Self::Supersaw { frequency, detune, mix, phases }
+    }
+
+    pub fn pulse(frequency: Number, amplitude: Number, duty_cycle: Number) -> Self {
+        Self::Pulse { frequency, amplitude, phase: Number::number(0.0), duty_cycle }
+    }
+
+    pub fn sine_pair(frequency: Number, detune_cents: f32, amplitude: Number) -> Self {
+        Self::SinePair { frequency, detune_cents, amplitude, upper_phase: 0.0 }
+    }
+
     pub fn white_noise(amplitude: Number) -> Self {
         Self::WhiteNoise { amplitude }
     }
@@ -210,20 +747,166 @@ impl WaveFunction {
         Self::PinkNoise { amplitude, generators, call_count: 0 }
     }
 
+    pub fn colored_noise(amplitude: Number, color: Number) -> Self {
+        Self::ColoredNoise { amplitude, color, filtered: 0.0, previous_white: 0.0 }
+    }
+
+    pub fn morph(a: WaveFunction, b: WaveFunction, mix: Number, frequency: Number) -> Self {
+        Self::Morph { a: Box::new(a), b: Box::new(b), mix, frequency }
+    }
+
+    pub fn custom(function: impl Fn(f32) -> f32 + Send + Sync + 'static, frequency: Number, amplitude: Number) -> Self {
+        Self::Custom { function: CustomWave(Arc::new(function)), frequency, amplitude }
+    }
+
+    /// Set the frequency of a periodic wave. Noise has no frequency, so it is left unchanged.
+    /// The accumulated phase is kept as is, so the output stays continuous across the change.
+    pub fn set_frequency(&mut self, freq: f32) {
+        match self {
+            WaveFunction::Sine { frequency, .. }
+            | WaveFunction::Square { frequency, .. }
+            | WaveFunction::Pulse { frequency, .. }
+            | WaveFunction::Triangle { frequency, .. }
+            | WaveFunction::Sawtooth { frequency, .. }
+            | WaveFunction::Custom { frequency, .. }
+            | WaveFunction::Morph { frequency, .. }
+            | WaveFunction::SinePair { frequency, .. }
+            | WaveFunction::Supersaw { frequency, .. } => *frequency = Number::number(freq),
+            WaveFunction::WhiteNoise { .. } | WaveFunction::PinkNoise { .. } | WaveFunction::ColoredNoise { .. } => {},
+        }
+    }
+
+    /// Multiply the frequency of the wave by `ratio`, keeping any modulation. Noise is left unchanged.
+    pub fn scale_frequency(&mut self, ratio: f32) {
+        match self {
+            WaveFunction::Sine { frequency, .. }
+            | WaveFunction::Square { frequency, .. }
+            | WaveFunction::Pulse { frequency, .. }
+            | WaveFunction::Triangle { frequency, .. }
+            | WaveFunction::Sawtooth { frequency, .. }
+            | WaveFunction::Custom { frequency, .. }
+            | WaveFunction::Morph { frequency, .. }
+            | WaveFunction::SinePair { frequency, .. }
+            | WaveFunction::Supersaw { frequency, .. } => {
+                let old_frequency = std::mem::replace(frequency, Number::number(0.0));
+                *frequency = old_frequency.mul_f32(ratio);
+            },
+            WaveFunction::WhiteNoise { .. } | WaveFunction::PinkNoise { .. } | WaveFunction::ColoredNoise { .. } => {},
+        }
+    }
+
+    /// The frequency of the wave, if it has one.
+    pub fn frequency(&self) -> Option<&Number> {
+        match self {
+            WaveFunction::Sine { frequency, .. }
+            | WaveFunction::Square { frequency, .. }
+            | WaveFunction::Pulse { frequency, .. }
+            | WaveFunction::Triangle { frequency, .. }
+            | WaveFunction::Sawtooth { frequency, .. }
+            | WaveFunction::Custom { frequency, .. }
+            | WaveFunction::Morph { frequency, .. }
+            | WaveFunction::SinePair { frequency, .. }
+            | WaveFunction::Supersaw { frequency, .. } => Some(frequency),
+            WaveFunction::WhiteNoise { .. } | WaveFunction::PinkNoise { .. } | WaveFunction::ColoredNoise { .. } => None,
+        }
+    }
+
+    /// The phase offset of the wave, if it has one.
+    pub fn phase_offset(&self) -> Option<&Number> {
+        match self {
+            WaveFunction::Sine { phase, .. }
+            | WaveFunction::Square { phase, .. }
+            | WaveFunction::Pulse { phase, .. }
+            | WaveFunction::Triangle { phase, .. }
+            | WaveFunction::Sawtooth { phase, .. } => Some(phase),
+            WaveFunction::WhiteNoise { .. }
+            | WaveFunction::PinkNoise { .. }
+            | WaveFunction::ColoredNoise { .. }
+            | WaveFunction::Custom { .. }
+            | WaveFunction::Morph { .. }
+            | WaveFunction::SinePair { .. }
+            | WaveFunction::Supersaw { .. } => None,
+        }
+    }
+
+    /// Replace the phase offset of a periodic wave.
+    /// If both the old and new offsets are constant, `accumulated_phase` is shifted by the difference
+    /// so the wave continues from the same point instead of jumping.
+    pub fn set_phase_offset(&mut self, new_phase: Number, accumulated_phase: &mut f32) {
+        match self {
+            WaveFunction::Sine { phase, .. }
+            | WaveFunction::Square { phase, .. }
+            | WaveFunction::Pulse { phase, .. }
+            | WaveFunction::Triangle { phase, .. }
+            | WaveFunction::Sawtooth { phase, .. } => {
+                if let (Some(old_offset), Some(new_offset)) = (phase.constant_value(), new_phase.constant_value()) {
+                    *accumulated_phase = (*accumulated_phase + old_offset - new_offset).rem_euclid(2.0 * PI);
+                }
+
+                *phase = new_phase;
+            },
+            WaveFunction::WhiteNoise { .. }
+            | WaveFunction::PinkNoise { .. }
+            | WaveFunction::ColoredNoise { .. }
+            | WaveFunction::Custom { .. }
+            | WaveFunction::Morph { .. }
+            | WaveFunction::SinePair { .. }
+            | WaveFunction::Supersaw { .. } => {},
+        }
+    }
+
+    /// Fill a whole grain with consecutive values, clamping frequencies below Nyquist.
+    pub fn next_grain(&mut self, accumulated_phase: &mut f32, dt: f32) -> Grain {
+        self.next_grain_with_policy(accumulated_phase, dt, NyquistPolicy::default())
+    }
+
+    /// Fill a whole grain with consecutive values, handling frequencies above Nyquist according to `policy`.
+    /// Sine waves with constant parameters skip the per-sample `Number` dispatch.
+    pub fn next_grain_with_policy(&mut self, accumulated_phase: &mut f32, dt: f32, policy: NyquistPolicy) -> Grain {
+        let mut grain = [0.0; SAMPLES_PER_GRAIN];
+
+        if let WaveFunction::Sine { frequency, amplitude, phase } = self
+            && let (Some(freq), Some(amp), Some(phase_offset)) = (frequency.constant_value(), amplitude.constant_value(), phase.constant_value())
+        {
+            let (freq, gain) = policy.limit(freq, dt);
+            let amp = amp * gain;
+            let phase_increment = 2.0 * PI * freq * dt;
+            for sample in &mut grain {
+                *accumulated_phase = (*accumulated_phase + phase_increment) % (2.0 * PI);
+                *sample = amp * (*accumulated_phase + phase_offset).sin();
+            }
+
+            return grain;
+        }
+
+        for sample in &mut grain {
+            *sample = self.next_value_with_policy(accumulated_phase, dt, policy);
+        }
+
+        grain
+    }
+
+    /// The next value of the wave, clamping frequencies below Nyquist.
     pub fn next_value(&mut self, accumulated_phase: &mut f32, dt: f32) -> f32 {
+        self.next_value_with_policy(accumulated_phase, dt, NyquistPolicy::default())
+    }
+
+    /// The next value of the wave, handling frequencies above Nyquist according to `policy`.
+    /// Noise has no frequency, so it is unaffected.
+    pub fn next_value_with_policy(&mut self, accumulated_phase: &mut f32, dt: f32, policy: NyquistPolicy) -> f32 {
         match self {
             WaveFunction::Sine { frequency, amplitude, phase } => {
-                let freq = frequency.next_value();
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
                 let amp = amplitude.next_value();
                 let phase_offset = phase.next_value();
 
                 *accumulated_phase += 2.0 * PI * freq * dt;
                 *accumulated_phase = *accumulated_phase % (2.0 * PI);
                 
-                amp * (*accumulated_phase + phase_offset).sin()
+                gain * amp * (*accumulated_phase + phase_offset).sin()
             },
             WaveFunction::Square { frequency, amplitude, phase } => {
-                let freq = frequency.next_value();
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
                 
                 *accumulated_phase += 2.0 * PI * freq * dt;
                 *accumulated_phase = *accumulated_phase % (2.0 * PI);
@@ -243,10 +926,31 @@ impl WaveFunction {
                 square -= poly_blep(shifted_phase, phase_increment);
 
                 let amp = amplitude.next_value();
-                amp * square
+                gain * amp * square
+            },
+            WaveFunction::Pulse { frequency, amplitude, phase, duty_cycle } => {
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
+                let amp = amplitude.next_value();
+                let phase_offset = phase.next_value();
+                let duty_cycle = duty_cycle.next_value().clamp(MIN_DUTY_CYCLE, 1.0 - MIN_DUTY_CYCLE);
+
+                *accumulated_phase = (*accumulated_phase + 2.0 * PI * freq * dt) % (2.0 * PI);
+
+                let normalized_phase = (*accumulated_phase + phase_offset) / (2.0 * PI);
+                let normalized_phase = normalized_phase - normalized_phase.floor();
+
+                let mut pulse = if normalized_phase < duty_cycle { 1.0 } else { -1.0 };
+
+                // smooth the rising edge at the start of the cycle and the falling edge at the duty cycle
+                let phase_increment = freq / *SAMPLE_RATE as f32;
+                pulse += poly_blep(normalized_phase, phase_increment);
+                let shifted_phase = (normalized_phase + 1.0 - duty_cycle) % 1.0;
+                pulse -= poly_blep(shifted_phase, phase_increment);
+
+                gain * amp * pulse
             },
             WaveFunction::Triangle { frequency, amplitude, phase } => {
-                let freq = frequency.next_value();
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
                 let amp = amplitude.next_value();
                 let phase_offset = phase.next_value();
 
@@ -257,16 +961,22 @@ impl WaveFunction {
                 let normalized_phase = (*accumulated_phase + phase_offset) / (2.0 * PI);
                 let normalized_phase = normalized_phase - normalized_phase.floor();
 
-                let triangle = if normalized_phase < 0.5 {
+                let mut triangle = if normalized_phase < 0.5 {
                     4.0 * normalized_phase - 1.0  // -1 to 1 for first half
                 } else {
                     3.0 - 4.0 * normalized_phase   // 1 to -1 for second half
                 };
 
-                amp * triangle
+                // smooth the corners, where the slope changes by 8 per cycle
+                let phase_increment = freq / *SAMPLE_RATE as f32;
+                triangle += 4.0 * phase_increment * poly_blamp(normalized_phase, phase_increment);
+                let shifted_phase = (normalized_phase + 0.5) % 1.0;
+                triangle -= 4.0 * phase_increment * poly_blamp(shifted_phase, phase_increment);
+
+                gain * amp * triangle
             },
             WaveFunction::Sawtooth { frequency, amplitude, phase } => {
-                let freq = frequency.next_value();
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
                 let amp = amplitude.next_value();
                 let phase_offset = phase.next_value();
 
@@ -277,41 +987,273 @@ impl WaveFunction {
                 let normalized_phase = (*accumulated_phase + phase_offset) / (2.0 * PI);
                 let normalized_phase = normalized_phase - normalized_phase.floor();
 
-                let mut sawtooth = 2.0 * normalized_phase - 1.0;
-
                 let phase_increment = freq / *SAMPLE_RATE as f32;
-                sawtooth -= poly_blep(normalized_phase, phase_increment);
+                let sawtooth = band_limited_sawtooth(normalized_phase, phase_increment);
 
-                amp * sawtooth
+                gain * amp * sawtooth
             },
             WaveFunction::WhiteNoise { amplitude } => {
                 let amp = amplitude.next_value();
-                let noise = rand::random_range(-1.0..=1.0);
+                let noise = noise_sample();
 
                 amp * noise
             },
             WaveFunction::PinkNoise { amplitude, generators, call_count } => {
-                // voss-mccartney
+                // voss-mccartney, with the generators staggered so exactly one updates per call.
+                // generator `i` updates every 2^(i + 1) calls, on the calls whose count has `i` trailing zeros,
+                // which spaces the generators an octave apart without all of them updating together.
                 let amp = amplitude.next_value();
 
-                if *call_count >= 2usize.pow(generators.len() as u32) {
-                    *call_count = 0;
+                *call_count = call_count.wrapping_add(1);
+                let index = call_count.trailing_zeros() as usize;
+                if index < generators.len() {
+                    generators[index] = noise_sample();
                 }
 
-                // update the generators
-                for i in 0..generators.len() {
-                    if *call_count % 2usize.pow(i as u32) == 0 {
-                        generators[i] = rand::random_range(-1.0..=1.0);
-                    }
-                }
+                // a white noise term fills in the top octave, which no generator covers
+                let white = noise_sample();
 
                 let scale_factor = 1.0 / 3.0f32.sqrt();
-                let noise = generators.iter().sum::<f32>() * scale_factor;
+                let noise = (generators.iter().sum::<f32>() + white) * scale_factor;
+
+                amp * noise
+            },
+            WaveFunction::ColoredNoise { amplitude, color, filtered, previous_white } => {
+                let amp = amplitude.next_value();
+                let color = color.next_value().clamp(-1.0, 1.0);
+                let white = noise_sample();
 
-                *call_count += 1;
+                // both filters always run, so modulating the color across 0 doesn't jump
+                let pole = color.max(0.0) * 0.995; // stop short of 1, where the filter would stop responding
+                *filtered = (1.0 - pole) * white + pole * *filtered;
+                let difference = white + color.min(0.0) * *previous_white;
+                *previous_white = white;
+
+                let noise = if color >= 0.0 {
+                    // a one-pole low-pass scales the power of white noise by (1 - p) / (1 + p)
+                    *filtered * ((1.0 + pole) / (1.0 - pole)).sqrt()
+                } else {
+                    difference / (1.0 + color * color).sqrt()
+                };
 
                 amp * noise
             },
+            WaveFunction::Custom { function, frequency, amplitude } => {
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
+                let amp = amplitude.next_value();
+
+                // rem_euclid keeps the phase positive when the frequency is negative
+                *accumulated_phase = (*accumulated_phase + 2.0 * PI * freq * dt).rem_euclid(2.0 * PI);
+
+                let normalized_phase = *accumulated_phase / (2.0 * PI);
+                let normalized_phase = normalized_phase - normalized_phase.floor();
+
+                gain * amp * (function.0)(normalized_phase)
+            },
+            WaveFunction::Morph { a, b, mix, frequency } => {
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
+                let mix = mix.next_value();
+
+                // play both children at the morph's frequency, each starting from the shared phase.
+                // noise children don't use the phase, so they are unaffected
+                a.set_frequency(freq);
+                b.set_frequency(freq);
+                let (mut a_phase, mut b_phase) = (*accumulated_phase, *accumulated_phase);
+                let a_value = a.next_value_with_policy(&mut a_phase, dt, policy);
+                let b_value = b.next_value_with_policy(&mut b_phase, dt, policy);
+
+                *accumulated_phase += 2.0 * PI * freq * dt;
+                *accumulated_phase = *accumulated_phase % (2.0 * PI);
+
+                gain * ((1.0 - mix) * a_value + mix * b_value)
+            },
+            WaveFunction::SinePair { frequency, detune_cents, amplitude, upper_phase } => {
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
+                let amp = amplitude.next_value();
+                let half_detune = 2.0f32.powf(*detune_cents / 2400.0);
+
+                *accumulated_phase = (*accumulated_phase + 2.0 * PI * freq / half_detune * dt) % (2.0 * PI);
+                *upper_phase = (*upper_phase + 2.0 * PI * freq * half_detune * dt) % (2.0 * PI);
+
+                // halved so the pair peaks at the same level as a single sine
+                gain * amp * 0.5 * (accumulated_phase.sin() + upper_phase.sin())
+            },
+            WaveFunction::Supersaw { frequency, detune, mix, phases } => {
+                let (freq, gain) = policy.limit(frequency.next_value(), dt);
+                let max_detune = supersaw_detune_curve(detune.next_value().clamp(0.0, 1.0));
+                let mix = mix.next_value().clamp(0.0, 1.0);
+
+                // the centre voice follows the shared phase, so phase-preserving wave changes still work
+                *accumulated_phase = (*accumulated_phase + 2.0 * PI * freq * dt) % (2.0 * PI);
+                let centre = band_limited_sawtooth(*accumulated_phase / (2.0 * PI), freq / *SAMPLE_RATE as f32);
+
+                // the side voices are spread evenly between the outermost detunes
+                let num_sides = phases.len();
+                let mut sides = 0.0;
+                for (i, side_phase) in phases.iter_mut().enumerate() {
+                    let spread = if num_sides > 1 { 2.0 * i as f32 / (num_sides - 1) as f32 - 1.0 } else { 1.0 };
+                    let side_freq = freq * (1.0 + max_detune * spread);
+
+                    *side_phase = (*side_phase + side_freq * dt).fract();
+                    sides += band_limited_sawtooth(*side_phase, side_freq / *SAMPLE_RATE as f32);
+                }
+
+                // the JP-8000's mix curves, with the sides scaled so more voices don't get much louder
+                let centre_gain = -0.55366 * mix + 0.99785;
+                let side_gain = -0.73764 * mix * mix + 1.2841 * mix + 0.044372;
+                let side_scale = if num_sides > 0 { 1.0 / (num_sides as f32).sqrt() } else { 0.0 };
+
+                gain * (centre_gain * centre + side_gain * side_scale * sides)
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SAMPLE_RATE: usize = 48000;
+
+    /// Render `num_samples` of a wave at the test sample rate.
+    fn render(wave_function: &mut WaveFunction, num_samples: usize) -> Vec<f32> {
+        crate::player::fix_sample_rate(TEST_SAMPLE_RATE);
+        let mut phase = 0.0;
+        let mut samples = Vec::with_capacity(num_samples + SAMPLES_PER_GRAIN);
+        while samples.len() < num_samples {
+            samples.extend(wave_function.next_grain(&mut phase, 1.0 / TEST_SAMPLE_RATE as f32));
+        }
+        samples.truncate(num_samples);
+
+        samples
+    }
+
+    /// The power in each frequency bin of a Hann-windowed block of samples, from 0 Hz up to Nyquist.
+    fn power_spectrum(samples: &[f32]) -> Vec<f32> {
+        let len = samples.len();
+        let (cos, sin): (Vec<f32>, Vec<f32>) = (0..len)
+            .map(|i| {
+                let angle = 2.0 * PI * i as f32 / len as f32;
+                (angle.cos(), angle.sin())
+            })
+            .unzip();
+        let windowed: Vec<f32> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| sample * (0.5 - 0.5 * cos[i]))
+            .collect();
+
+        (0..=len / 2)
+            .map(|bin| {
+                let (re, im) = windowed.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, sample)| {
+                    let twiddle = bin * i % len;
+                    (re + sample * cos[twiddle], im - sample * sin[twiddle])
+                });
+                re * re + im * im
+            })
+            .collect()
+    }
+
+    /// The fraction of a periodic wave's power that is not at one of its harmonics below Nyquist, i.e. aliasing.
+    fn aliased_fraction(samples: &[f32], frequency: f32) -> f32 {
+        let spectrum = power_spectrum(samples);
+        let bin_width = TEST_SAMPLE_RATE as f32 / samples.len() as f32;
+        let is_harmonic = |bin: usize| {
+            let harmonic = (bin as f32 * bin_width / frequency).round().max(1.0);
+            (bin as f32 * bin_width - harmonic * frequency).abs() <= 3.0 * bin_width
+        };
+
+        let total: f32 = spectrum.iter().sum();
+        let aliased: f32 = spectrum.iter().enumerate().filter(|(bin, _)| !is_harmonic(*bin)).map(|(_, power)| power).sum();
+
+        aliased / total
+    }
+
+    #[test]
+    fn triangle_aliases_less_than_a_naive_triangle() {
+        // not a divisor of the sample rate, so aliases land between the harmonics instead of on them
+        let frequency = 4100.0;
+        let mut triangle = WaveFunction::Triangle {
+            frequency: Number::number(frequency),
+            amplitude: Number::number(1.0),
+            phase: Number::number(0.0),
+        };
+        let band_limited = render(&mut triangle, 4800);
+        let naive: Vec<f32> = (1..=4800)
+            .map(|i| {
+                let phase = (frequency * i as f32 / TEST_SAMPLE_RATE as f32).fract();
+                if phase < 0.5 { 4.0 * phase - 1.0 } else { 3.0 - 4.0 * phase }
+            })
+            .collect();
+
+        let (band_limited, naive) = (aliased_fraction(&band_limited, frequency), aliased_fraction(&naive, frequency));
+        assert!(band_limited < 0.1 * naive, "{band_limited} of the power aliased, against {naive} for a naive triangle");
+    }
+
+    #[test]
+    fn pink_noise_falls_3_db_per_octave() {
+        seed_noise(1);
+        let block = 1024;
+        let samples = render(&mut WaveFunction::pink_noise(Number::number(1.0), 16), 64 * block);
+
+        // average the spectra of many blocks, so the noise's own randomness evens out
+        let mut spectrum = vec![0.0; block / 2 + 1];
+        for chunk in samples.chunks(block) {
+            for (sum, power) in spectrum.iter_mut().zip(power_spectrum(chunk)) {
+                *sum += power;
+            }
+        }
+
+        // the mean power per bin in octaves from 187.5 Hz to 12 kHz, in dB
+        let levels: Vec<f32> = (2..8)
+            .map(|octave| {
+                let bins = &spectrum[1 << octave..2 << octave];
+                10.0 * (bins.iter().sum::<f32>() / bins.len() as f32).log10()
+            })
+            .collect();
+
+        // least-squares slope of level against octave
+        let mean_octave = (levels.len() - 1) as f32 / 2.0;
+        let mean_level = levels.iter().sum::<f32>() / levels.len() as f32;
+        let (covariance, variance) = levels.iter().enumerate().fold((0.0, 0.0), |(covariance, variance), (octave, level)| {
+            let octave = octave as f32 - mean_octave;
+            (covariance + octave * (level - mean_level), variance + octave * octave)
+        });
+        let slope = covariance / variance;
+
+        assert!((slope + 3.0).abs() < 0.5, "slope of {slope} dB per octave, levels {levels:?}");
+    }
+
+    #[test]
+    fn sine_fast_path_matches_per_sample_path() {
+        crate::player::fix_sample_rate(TEST_SAMPLE_RATE);
+        let sine = WaveFunction::Sine {
+            frequency: Number::number(440.0),
+            amplitude: Number::number(0.8),
+            phase: Number::number(0.3),
+        };
+        let dt = 1.0 / TEST_SAMPLE_RATE as f32;
+
+        let (mut grain_wave, mut grain_phase) = (sine.clone(), 0.0);
+        let (mut sample_wave, mut sample_phase) = (sine, 0.0);
+        for _ in 0..100 {
+            let grain = grain_wave.next_grain(&mut grain_phase, dt);
+            for sample in grain {
+                assert!((sample - sample_wave.next_value(&mut sample_phase, dt)).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_waves_only_see_phases_from_0_to_1() {
+        let mut wave_function = WaveFunction::custom(
+            |phase| {
+                assert!((0.0..1.0).contains(&phase), "phase {phase} is out of range");
+                phase
+            },
+            Number::number(-440.0),
+            Number::number(1.0),
+        );
+        render(&mut wave_function, 4 * SAMPLES_PER_GRAIN);
+    }
+}
@@ -0,0 +1,85 @@
+use super::ADSR;
+use crate::player::SAMPLE_RATE;
+use std::sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc};
+
+/// Shares an oscillator's note presses and releases with the `Number::note_envelope`s listening to it.
+/// Give a clone to `OscillatorBuilder::note_gate` and another to each envelope. Clones all share the same notes.
+#[derive(Clone, Debug, Default)]
+pub struct NoteGate {
+    presses: Arc<AtomicU64>, // counts presses, so an envelope can tell a new note from one it has already started
+    held: Arc<AtomicBool>,
+}
+
+impl NoteGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn press(&self) {
+        self.presses.fetch_add(1, Ordering::Relaxed);
+        self.held.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn release(&self) {
+        self.held.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Stage {
+    Idle,
+    Held,
+    Release { from: f32 },
+}
+
+/// An ADSR envelope that restarts whenever the oscillator it listens to is pressed, and releases when it is released.
+#[derive(Clone, Debug)]
+pub struct NoteEnvelope {
+    adsr: ADSR,
+    gate: NoteGate,
+    presses_seen: u64,
+    stage: Stage,
+    secs: f32, // since the current stage started
+    level: f32,
+}
+
+impl NoteEnvelope {
+    pub fn new(adsr: ADSR, gate: NoteGate) -> Self {
+        Self {
+            adsr,
+            gate,
+            presses_seen: 0,
+            stage: Stage::Idle,
+            secs: 0.0,
+            level: 0.0,
+        }
+    }
+
+    pub fn next_value(&mut self) -> f32 {
+        let presses = self.gate.presses.load(Ordering::Relaxed);
+        if presses != self.presses_seen {
+            self.presses_seen = presses;
+            self.stage = Stage::Held;
+            self.secs = 0.0;
+        } else if matches!(self.stage, Stage::Held) && !self.gate.held.load(Ordering::Relaxed) {
+            // release from wherever the envelope has got to, so releasing mid-attack doesn't jump
+            self.stage = Stage::Release { from: self.level };
+            self.secs = 0.0;
+        }
+
+        self.level = match self.stage {
+            Stage::Idle => 0.0,
+            Stage::Held => self.adsr.held_amplitude(self.secs),
+            Stage::Release { from } => match self.adsr.release_amplitude(from, self.secs) {
+                Some(level) => level,
+                None => {
+                    self.stage = Stage::Idle;
+                    0.0
+                },
+            },
+        };
+        self.secs += 1.0 / *SAMPLE_RATE as f32;
+
+        self.level
+    }
+}
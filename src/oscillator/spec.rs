@@ -0,0 +1,169 @@
+use super::{try_note, NoteParseError, Oscillator, OscillatorBuilder, WaveFunction, ADSR};
+use crate::{effects::{Effect, Filter, Volume}, Number};
+use std::{error::Error, fmt::{self, Display, Formatter}};
+
+/// The number of poles of the filters in a spec.
+const SPEC_FILTER_POLES: usize = 4;
+
+/// An error from parsing an oscillator spec. See `Oscillator::from_spec`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpecParseError {
+    MissingWave,
+    UnknownWave(String),
+    MissingNote,
+    InvalidNote(NoteParseError),
+    UnknownToken(String),
+    WrongArgumentCount { token: String, expected: usize, found: usize },
+    InvalidNumber(String),
+    UnclosedParenthesis(String),
+}
+
+impl Display for SpecParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecParseError::MissingWave => write!(f, "the spec is empty; it must start with a wave"),
+            SpecParseError::UnknownWave(wave) => write!(f, "unknown wave `{wave}`; expected sine, square, triangle or saw"),
+            SpecParseError::MissingNote => write!(f, "the spec has no note after the wave"),
+            SpecParseError::InvalidNote(err) => write!(f, "{err}"),
+            SpecParseError::UnknownToken(token) => write!(f, "unknown token `{token}`; expected adsr, lpf, hpf or vol"),
+            SpecParseError::WrongArgumentCount { token, expected, found } => write!(f, "`{token}` takes {expected} arguments, not {found}"),
+            SpecParseError::InvalidNumber(number) => write!(f, "`{number}` is not a number"),
+            SpecParseError::UnclosedParenthesis(token) => write!(f, "`{token}` has an unclosed parenthesis"),
+        }
+    }
+}
+
+impl Error for SpecParseError {}
+
+/// Split a spec on whitespace outside parentheses, so arguments can be spaced out.
+fn tokens(spec: &str) -> Result<Vec<&str>, SpecParseError> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut depth = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => {
+                if let Some(start) = start.take() {
+                    tokens.push(&spec[start..i]);
+                }
+                continue;
+            },
+            _ => {},
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        if depth != 0 {
+            return Err(SpecParseError::UnclosedParenthesis(spec[start..].to_string()));
+        }
+        tokens.push(&spec[start..]);
+    }
+
+    Ok(tokens)
+}
+
+/// Split a token like `lpf(600, 0.5)` into its name and arguments.
+fn call(token: &str) -> Result<(&str, Vec<f32>), SpecParseError> {
+    let Some((name, arguments)) = token.split_once('(') else {
+        return Err(SpecParseError::UnknownToken(token.to_string()));
+    };
+    let arguments = arguments
+        .strip_suffix(')')
+        .ok_or_else(|| SpecParseError::UnclosedParenthesis(token.to_string()))?;
+
+    // `name()` has no arguments, but an empty argument between commas is a mistake
+    if arguments.trim().is_empty() {
+        return Ok((name, Vec::new()));
+    }
+    let arguments = arguments
+        .split(',')
+        .map(str::trim)
+        .map(|argument| argument.parse().map_err(|_| SpecParseError::InvalidNumber(argument.to_string())))
+        .collect::<Result<_, _>>()?;
+
+    Ok((name, arguments))
+}
+
+fn expect_arguments<const N: usize>(token: &str, arguments: &[f32]) -> Result<[f32; N], SpecParseError> {
+    arguments.try_into().map_err(|_| SpecParseError::WrongArgumentCount {
+        token: token.to_string(),
+        expected: N,
+        found: arguments.len(),
+    })
+}
+
+impl Oscillator {
+    /// Build an auto-playing oscillator from a short spec, e.g. `"saw C3 adsr(0.1, 0.1, 0.8, 0.3) lpf(600, 0.5)"`.
+    ///
+    /// A spec is a wave (`sine`, `square`, `triangle` or `saw`) and a note, followed by any of:
+    /// - `adsr(attack, decay, sustain, release)`, the envelope;
+    /// - `lpf(cutoff, resonance)` and `hpf(cutoff, resonance)`, 4-pole low- and high-pass filters;
+    /// - `vol(volume)`, a volume effect.
+    ///
+    /// Effects are applied in the order they are written. Anything else is an error.
+    pub fn from_spec(spec: &str) -> Result<Oscillator, SpecParseError> {
+        let tokens = tokens(spec)?;
+        let mut tokens = tokens.into_iter();
+
+        let wave = tokens.next().ok_or(SpecParseError::MissingWave)?;
+        let note_name = tokens.next().ok_or(SpecParseError::MissingNote)?;
+        let frequency = Number::number(try_note(note_name).map_err(SpecParseError::InvalidNote)?);
+        let (amplitude, phase) = (Number::number(1.0), Number::number(0.0));
+        let wave_function = match wave {
+            "sine" => WaveFunction::Sine { frequency, amplitude, phase },
+            "square" => WaveFunction::Square { frequency, amplitude, phase },
+            "triangle" => WaveFunction::Triangle { frequency, amplitude, phase },
+            "saw" => WaveFunction::Sawtooth { frequency, amplitude, phase },
+            _ => return Err(SpecParseError::UnknownWave(wave.to_string())),
+        };
+
+        let mut builder = OscillatorBuilder::new().wave_function(wave_function).auto_play();
+        for token in tokens {
+            let (name, arguments) = call(token)?;
+            builder = match name {
+                "adsr" => {
+                    let [attack, decay, sustain, release] = expect_arguments(token, &arguments)?;
+                    builder.adsr(ADSR::new(attack, decay, sustain, release))
+                },
+                "lpf" => {
+                    let [cutoff, resonance] = expect_arguments(token, &arguments)?;
+                    builder.effect(Effect::Filter(Filter::new_low_pass(Number::number(cutoff), Number::number(resonance), SPEC_FILTER_POLES)))
+                },
+                "hpf" => {
+                    let [cutoff, resonance] = expect_arguments(token, &arguments)?;
+                    builder.effect(Effect::Filter(Filter::new_high_pass(Number::number(cutoff), Number::number(resonance), SPEC_FILTER_POLES)))
+                },
+                "vol" => {
+                    let [volume] = expect_arguments(token, &arguments)?;
+                    builder.effect(Effect::Volume(Volume(Number::number(volume))))
+                },
+                _ => return Err(SpecParseError::UnknownToken(token.to_string())),
+            };
+        }
+
+        Ok(builder.build())
+    }
+}
+
+impl TryFrom<&str> for Oscillator {
+    type Error = SpecParseError;
+
+    fn try_from(spec: &str) -> Result<Self, Self::Error> {
+        Oscillator::from_spec(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_arguments_are_invalid_numbers() {
+        assert_eq!(call("adsr(0.1,,0.1,0.8,0.3)"), Err(SpecParseError::InvalidNumber(String::new())));
+        assert_eq!(call("adsr(0.1, 0.1, 0.8, 0.3,)"), Err(SpecParseError::InvalidNumber(String::new())));
+        assert_eq!(call("name()"), Ok(("name", Vec::new())));
+        assert_eq!(call("name( )"), Ok(("name", Vec::new())));
+    }
+}
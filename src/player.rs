@@ -1,49 +1,349 @@
-use crate::sound::{Grain, SAMPLES_PER_GRAIN, SoundTrait};
+use crate::{oscillator::seed_noise, sound::{beats_to_secs, Composition, Grain, SAMPLES_PER_GRAIN, SoundTrait}};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, Host, Stream, StreamConfig, BufferSize};
-use std::sync::{Arc, Mutex, LazyLock};
+use cpal::{Device, Host, Stream, StreamConfig, BufferSize, SupportedBufferSize, SupportedStreamConfig};
+use std::{error::Error, fmt::{self, Display, Formatter}, sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc, Condvar, Mutex, LazyLock, OnceLock}};
 
-static HOST: LazyLock<Host> = LazyLock::new(cpal::default_host);
+pub(crate) static HOST: LazyLock<Host> = LazyLock::new(cpal::default_host);
+static FIXED_SAMPLE_RATE: OnceLock<usize> = OnceLock::new();
 pub static SAMPLE_RATE: LazyLock<usize> = LazyLock::new(|| {
+    if let Some(sample_rate) = FIXED_SAMPLE_RATE.get() {
+        return *sample_rate;
+    }
+
     let device = HOST.default_output_device().unwrap();
     let default_config = device.default_output_config().unwrap();
 
     default_config.sample_rate().0 as usize
 });
 
-pub fn play_sound(sound: &mut dyn SoundTrait) {
+/// Whether there is a default output device to play on, e.g. false in most CI machines and containers.
+/// Some hosts name a default device even when there is no sound card behind it, so this also checks it can be configured.
+pub fn has_output_device() -> bool {
+    HOST.default_output_device().is_some_and(|device| device.default_output_config().is_ok())
+}
+
+/// Use `sample_rate` everywhere instead of asking the output device, e.g. for offline renders.
+/// The sample rate can only be chosen once, before anything reads it, so this returns false if it is already
+/// set to something else.
+pub fn fix_sample_rate(sample_rate: usize) -> bool {
+    let _ = FIXED_SAMPLE_RATE.set(sample_rate);
+
+    *SAMPLE_RATE == sample_rate
+}
+
+/// Settings for offline renders that come out identical every time.
+///
+/// Run to run, a render can differ because noise is randomly seeded and the sample rate comes from
+/// whatever output device is present. Rendering through this seeds the noise and fixes the sample rate, so the
+/// same sound renders to the same samples on any machine with the same platform and build.
+/// Drift is already seeded, and live input is inherently not reproducible.
+#[derive(Clone, Debug)]
+pub struct DeterministicConfig {
+    pub seed: u64,
+    pub sample_rate: usize,
+}
+
+impl DeterministicConfig {
+    pub fn new(seed: u64, sample_rate: usize) -> Self {
+        Self { seed, sample_rate }
+    }
+
+    /// Render the next `num_samples` samples of a sound. Panics if a different sample rate is already in use.
+    pub fn render(&self, sound: &mut dyn SoundTrait, num_samples: usize) -> Vec<f32> {
+        assert!(fix_sample_rate(self.sample_rate), "the sample rate is already set to {}", *SAMPLE_RATE);
+        seed_noise(self.seed);
+
+        sound.update_sample_rate(self.sample_rate);
+        render_samples(sound, num_samples)
+    }
+}
+
+/// How samples outside [-1, 1] are brought into range before they are sent to the device.
+/// Without this, integer output formats can wrap out-of-range samples around into loud noise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Clipping {
+    /// Clamp to [-1, 1], leaving samples within range untouched.
+    #[default]
+    Hard,
+    /// Pass every sample through tanh, which rounds off peaks but also colours quieter samples slightly.
+    Soft,
+}
+
+impl Clipping {
+    fn apply(self, sample: f32) -> f32 {
+        match self {
+            Clipping::Hard => sample.clamp(-1.0, 1.0),
+            Clipping::Soft => sample.tanh(),
+        }
+    }
+}
+
+/// Which of the device's output channels the (mono) signal is sent to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelRouting {
+    /// Copy the signal to every channel. Fine for mono and stereo devices.
+    #[default]
+    All,
+    /// Send the signal to the first two channels, front left and right on common surround layouts,
+    /// and silence the rest, so e.g. a 5.1 device doesn't play it from the centre, rears and subwoofer too.
+    FrontPair,
+}
+
+impl ChannelRouting {
+    fn plays_on(self, channel: usize) -> bool {
+        match self {
+            ChannelRouting::All => true,
+            ChannelRouting::FrontPair => channel < 2,
+        }
+    }
+}
+
+/// Settings for playback.
+#[derive(Clone, Debug, Default)]
+pub struct PlayerConfig {
+    pub clipping: Clipping,
+    pub channel_routing: ChannelRouting,
+}
+
+/// Levels measured on the audio thread, for meters. Every output channel carries the same signal, so one meter covers them all.
+#[derive(Debug, Default)]
+struct Meter {
+    peak: AtomicU32, // the bits of an f32, since there is no atomic float
+    clipped: AtomicBool,
+}
+
+/// A sound playing on the default output device. Playback stops when the handle is stopped or dropped.
+///
+/// Several handles can exist at once, each owning its own stream on the device, so they can be
+/// started and stopped independently. Mixing the streams is left to the host: most backends do this,
+/// but some (e.g. a raw ALSA `hw` device without `dmix`) only allow one stream per device,
+/// in which case starting a second handle panics.
+pub struct PlaybackHandle {
+    stream: Stream,
+    meter: Arc<Meter>,
+    buffer_size: Option<u32>,
+}
+
+impl PlaybackHandle {
+    /// The buffer size the stream was opened with, in frames, or `None` if the device chose its own.
+    /// A grain's worth is asked for, clamped into the range the device supports.
+    pub fn buffer_size(&self) -> Option<u32> {
+        self.buffer_size
+    }
+
+    /// The loudest absolute sample in the most recent buffer sent to the device, before clipping.
+    pub fn current_peak(&self) -> f32 {
+        f32::from_bits(self.meter.peak.load(Ordering::Relaxed))
+    }
+
+    /// Whether any sample has been outside [-1, 1] since this was last called.
+    pub fn clipped_since_last_check(&self) -> bool {
+        self.meter.clipped.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn pause(&self) {
+        self.stream.pause().unwrap();
+    }
+
+    pub fn resume(&self) {
+        self.stream.play().unwrap();
+    }
+
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+/// Start playing a sound without blocking, returning a handle that controls it.
+/// The sound is cloned, so later changes to `sound` are not heard; see `start_shared_sound` for that.
+pub fn start_sound(sound: &mut dyn SoundTrait) -> PlaybackHandle {
+    start_sound_with_config(sound, &PlayerConfig::default())
+}
+
+/// Like `start_sound`, with playback settings.
+pub fn start_sound_with_config(sound: &mut dyn SoundTrait, config: &PlayerConfig) -> PlaybackHandle {
+    start_stream(Arc::new(Mutex::new(sound.clone_box())), config)
+}
+
+/// Start playing a shared sound without blocking. The sound can be changed while it plays,
+/// e.g. adding sounds to a `Composition`, by locking it. Keep locks short to avoid dropouts.
+pub fn start_shared_sound<S: SoundTrait + 'static>(sound: Arc<Mutex<S>>) -> PlaybackHandle {
+    start_stream(sound, &PlayerConfig::default())
+}
+
+/// A grain's worth of frames, clamped into the range the device supports, or `None` if it doesn't say.
+fn fixed_buffer_size(supported: &SupportedBufferSize) -> Option<u32> {
+    match supported {
+        SupportedBufferSize::Range { min, max } => Some((SAMPLES_PER_GRAIN as u32).clamp(*min, *max)),
+        SupportedBufferSize::Unknown => None,
+    }
+}
+
+fn start_stream(sound: Arc<Mutex<dyn SoundTrait>>, config: &PlayerConfig) -> PlaybackHandle {
     let device = HOST.default_output_device().unwrap();
     let default_config = device.default_output_config().unwrap();
 
+    sound.lock().unwrap().update_sample_rate(*SAMPLE_RATE);
+    let meter = Arc::new(Meter::default());
+
+    let mut buffer_size = fixed_buffer_size(default_config.buffer_size());
+    let stream = match open_stream(&device, &default_config, buffer_size, &sound, config, &meter) {
+        Ok(stream) => stream,
+        // some devices report a range but still reject fixed sizes, so let the device choose
+        Err(err) if buffer_size.is_some() => {
+            eprintln!("Couldn't open the stream with a buffer of {} frames ({err}), using the device's default", buffer_size.unwrap());
+            buffer_size = None;
+            open_stream(&device, &default_config, None, &sound, config, &meter).unwrap()
+        },
+        Err(err) => panic!("Couldn't open the stream: {err}"),
+    };
+
+    stream.play().unwrap();
+
+    PlaybackHandle { stream, meter, buffer_size }
+}
+
+/// Open an output stream with a fixed buffer size, or the device's default for `None`.
+fn open_stream(
+    device: &Device,
+    default_config: &SupportedStreamConfig,
+    buffer_size: Option<u32>,
+    sound: &Arc<Mutex<dyn SoundTrait>>,
+    config: &PlayerConfig,
+    meter: &Arc<Meter>,
+) -> Result<Stream, cpal::BuildStreamError> {
     let mut stream_config: StreamConfig = default_config.clone().into();
-    stream_config.buffer_size = BufferSize::Fixed(SAMPLES_PER_GRAIN as u32);
+    stream_config.buffer_size = buffer_size.map_or(BufferSize::Default, BufferSize::Fixed);
 
     let err_fn = |err| eprintln!("Audio stream error: {err}");
-
-    let stream = match default_config.sample_format() {
-        cpal::SampleFormat::F32 => {
-            sound.update_sample_rate(*SAMPLE_RATE);
-            build_stream::<f32>(&device, &stream_config, vec![sound.clone_box()], err_fn)
-        },
-        cpal::SampleFormat::I16 => {
-            sound.update_sample_rate(*SAMPLE_RATE);
-            build_stream::<i16>(&device, &stream_config, vec![sound.clone_box()], err_fn)
-        },
-        cpal::SampleFormat::U16 => {
-            sound.update_sample_rate(*SAMPLE_RATE);
-            build_stream::<u16>(&device, &stream_config, vec![sound.clone_box()], err_fn)
-        },
+    let sounds = vec![sound.clone()];
+    match default_config.sample_format() {
+        cpal::SampleFormat::F32 => build_stream::<f32>(device, &stream_config, sounds, config, meter.clone(), err_fn),
+        cpal::SampleFormat::I16 => build_stream::<i16>(device, &stream_config, sounds, config, meter.clone(), err_fn),
+        cpal::SampleFormat::U16 => build_stream::<u16>(device, &stream_config, sounds, config, meter.clone(), err_fn),
         _ => panic!("Unsupported sample format"),
-    }.unwrap();
+    }
+}
 
-    stream.play().unwrap();
+/// Counts calls to `stop_playing`, so each blocking `play_sound` can tell when it has been asked to stop.
+static STOP_REQUESTS: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
 
-    // keep the stream alive
-    std::thread::park();
+/// Play a sound, blocking the current thread until `stop_playing` is called.
+pub fn play_sound(sound: &mut dyn SoundTrait) {
+    let (requests, stopped) = &STOP_REQUESTS;
+    let started_at = *requests.lock().unwrap();
+    let handle = start_sound(sound);
+
+    let _requests = stopped.wait_while(requests.lock().unwrap(), |requests| *requests == started_at).unwrap();
+
+    // pause before dropping, so the device isn't left holding a half-played buffer
+    handle.pause();
+    handle.stop();
+}
+
+/// Stop every `play_sound` that is currently blocking, letting each drop its stream cleanly and return.
+/// This is safe to call from any thread, e.g. a Ctrl-C handler.
+pub fn stop_playing() {
+    let (requests, stopped) = &STOP_REQUESTS;
+    *requests.lock().unwrap() += 1;
+    stopped.notify_all();
+}
+
+/// Render a sound offline, without playing it, returning the next `num_samples` samples.
+pub fn render_samples(sound: &mut dyn SoundTrait, num_samples: usize) -> Vec<f32> {
+    let mut samples = Vec::with_capacity(num_samples + SAMPLES_PER_GRAIN);
+    while samples.len() < num_samples {
+        samples.extend(sound.next_grain());
+    }
+    samples.truncate(num_samples);
+
+    samples
+}
+
+/// The number of beats in a bar. Only 4/4 time is supported.
+pub const BEATS_PER_BAR: usize = 4;
+
+/// Render exactly `bars` bars of a sound at `bpm`, e.g. to bounce a loop.
+/// The sound plays continuously, so effect tails from earlier bars ring on into the later ones,
+/// but anything still ringing after the final bar is cut off.
+pub fn render_bars(sound: &mut dyn SoundTrait, bars: usize, bpm: f32) -> Vec<f32> {
+    let secs = beats_to_secs(bpm, (bars * BEATS_PER_BAR) as f32);
+    let num_samples = (secs * *SAMPLE_RATE as f32).round() as usize;
+
+    sound.update_sample_rate(*SAMPLE_RATE);
+    render_samples(sound, num_samples)
+}
+
+/// An error from `bounce`.
+#[derive(Debug)]
+pub enum BounceError {
+    /// The sample rate was already fixed to `fixed`, so the composition can't be rendered at another.
+    SampleRateAlreadyFixed { fixed: usize, requested: usize },
+    Wav(hound::Error),
+}
+
+impl Display for BounceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BounceError::SampleRateAlreadyFixed { fixed, requested } => {
+                write!(f, "can't render at {requested} Hz, since the sample rate is already fixed to {fixed} Hz")
+            },
+            BounceError::Wav(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for BounceError {}
+
+impl From<hound::Error> for BounceError {
+    fn from(err: hound::Error) -> Self {
+        BounceError::Wav(err)
+    }
+}
+
+/// Render `duration_secs` of a composition and write it to a mono 32-bit float WAV file at `path`.
+/// If any sample would clip, the whole render is scaled down so the loudest peak is at full scale.
+///
+/// This fixes the sample rate to `sample_rate` (see `fix_sample_rate`), so no output device is needed, and fails
+/// if it is already fixed to something else, e.g. by playing or building sounds that read it first.
+pub fn bounce(mut composition: Composition, duration_secs: f32, sample_rate: usize, path: &str) -> Result<(), BounceError> {
+    if !fix_sample_rate(sample_rate) {
+        return Err(BounceError::SampleRateAlreadyFixed { fixed: *SAMPLE_RATE, requested: sample_rate });
+    }
+    composition.update_sample_rate(sample_rate);
+    let num_samples = (duration_secs * sample_rate as f32) as usize;
+    let mut samples = render_samples(&mut composition, num_samples);
+
+    let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    if peak > 1.0 {
+        for sample in &mut samples {
+            *sample /= peak;
+        }
+    }
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in samples {
+        writer.write_sample(sample)?;
+    }
+
+    writer.finalize()?;
+
+    Ok(())
 }
 
 fn combine_grains(grains: Vec<Grain>) -> Vec<f32> {
-    let mut combined = vec![0.0; grains[0].len()];
+    // with nothing to play, return silence rather than dividing by zero below
+    if grains.is_empty() {
+        return vec![0.0; SAMPLES_PER_GRAIN];
+    }
+
+    let mut combined = vec![0.0; SAMPLES_PER_GRAIN];
     for grain in &grains {
         for (i, sample) in grain.iter().enumerate() {
             combined[i] += sample;
@@ -60,31 +360,33 @@ fn combine_grains(grains: Vec<Grain>) -> Vec<f32> {
 fn build_stream<T>(
     device: &Device,
     config: &StreamConfig,
-    sounds: Vec<Box<dyn SoundTrait>>,
+    sounds: Vec<Arc<Mutex<dyn SoundTrait>>>,
+    player_config: &PlayerConfig,
+    meter: Arc<Meter>,
     err_fn: impl Fn(cpal::StreamError) + Send + 'static,
 ) -> Result<Stream, cpal::BuildStreamError>
 where
     T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
 {
     let channels = config.channels as usize;
-    let sounds = Arc::new(Mutex::new(sounds));
+    let clipping = player_config.clipping;
+    let channel_routing = player_config.channel_routing;
     let current_grain = Arc::new(Mutex::new(Vec::<f32>::new()));
     let grain_position = Arc::new(Mutex::new(0usize));
 
     device.build_output_stream(
         config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut peak = 0.0f32;
             for frame in data.chunks_mut(channels) {
                 let mut grain_pos = grain_position.lock().unwrap();
                 let mut current_grain_guard = current_grain.lock().unwrap();
                 
                 // Check if we need to get a new grain
                 if current_grain_guard.is_empty() || *grain_pos >= current_grain_guard.len() {
-                    let mut sounds_guard = sounds.lock().unwrap();
-                    let grains = sounds_guard.iter_mut().map(|sound| sound.next_grain()).collect::<Vec<_>>();
+                    let grains = sounds.iter().map(|sound| sound.lock().unwrap().next_grain()).collect::<Vec<_>>();
                     *current_grain_guard = combine_grains(grains);
                     *grain_pos = 0;
-                    drop(sounds_guard); // Release the lock early
                 }
                 
                 // Get the current sample from the grain
@@ -98,12 +400,117 @@ where
                 drop(current_grain_guard);
                 drop(grain_pos);
 
-                for channel_sample in frame.iter_mut() {
+                peak = peak.max(sample.abs());
+                let sample = clipping.apply(sample);
+                for (channel, channel_sample) in frame.iter_mut().enumerate() {
+                    let sample = if channel_routing.plays_on(channel) { sample } else { 0.0 };
                     *channel_sample = T::from_sample(sample);
                 }
             }
+
+            meter.peak.store(peak.to_bits(), Ordering::Relaxed);
+            if peak > 1.0 {
+                meter.clipped.store(true, Ordering::Relaxed);
+            }
         },
         err_fn,
         None,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{effects::Effect, sound::CompositionBuilder};
+    use std::{sync::atomic::AtomicUsize, thread, time::Duration};
+
+    /// A silent sound that counts the grains pulled from it, to tell whether its stream is running.
+    #[derive(Clone, Default)]
+    struct GrainCounter(Arc<AtomicUsize>);
+
+    impl GrainCounter {
+        fn grains(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl SoundTrait for GrainCounter {
+        fn next_sample(&mut self) -> f32 {
+            0.0
+        }
+
+        fn next_grain(&mut self) -> Grain {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            [0.0; SAMPLES_PER_GRAIN]
+        }
+
+        fn add_effect(&mut self, _effect: Effect) {}
+
+        fn update_sample_rate(&mut self, _sample_rate: usize) {}
+
+        fn clone_box(&self) -> Box<dyn SoundTrait> {
+            Box::new(self.clone())
+        }
+
+        fn secs_per_beat(&self) -> Option<f32> {
+            None
+        }
+    }
+
+    /// How many grains `counter` plays over a short wait, letting any callback already running finish first.
+    fn grains_played(counter: &GrainCounter) -> usize {
+        thread::sleep(Duration::from_millis(50));
+        let before = counter.grains();
+        thread::sleep(Duration::from_millis(200));
+
+        counter.grains() - before
+    }
+
+    #[test]
+    fn no_grains_combine_into_silence() {
+        assert_eq!(combine_grains(Vec::new()), vec![0.0; SAMPLES_PER_GRAIN]);
+    }
+
+    #[test]
+    fn empty_composition_renders_silence() {
+        fix_sample_rate(48000);
+        let mut composition = CompositionBuilder::new().build();
+        let grain = composition.next_grain();
+        assert_eq!(combine_grains(vec![grain]), vec![0.0; SAMPLES_PER_GRAIN]);
+        assert!(render_samples(&mut composition, 4 * SAMPLES_PER_GRAIN).iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn handles_stop_and_pause_independently() {
+        // needs a real output device, which CI machines and containers usually lack
+        if !has_output_device() {
+            return;
+        }
+
+        let (first, second) = (GrainCounter::default(), GrainCounter::default());
+        let first_handle = start_shared_sound(Arc::new(Mutex::new(first.clone())));
+        let second_handle = start_shared_sound(Arc::new(Mutex::new(second.clone())));
+        assert!(grains_played(&first) > 0 && grains_played(&second) > 0);
+
+        first_handle.stop();
+        assert_eq!(grains_played(&first), 0);
+        assert!(grains_played(&second) > 0);
+
+        second_handle.pause();
+        assert_eq!(grains_played(&second), 0);
+        second_handle.resume();
+        assert!(grains_played(&second) > 0);
+    }
+
+    #[test]
+    fn bounce_refuses_a_second_sample_rate() {
+        fix_sample_rate(48000);
+        let path = std::env::temp_dir().join("gran_bounce_test.wav");
+        let result = bounce(CompositionBuilder::new().build(), 0.1, 44100, path.to_str().unwrap());
+        assert!(matches!(result, Err(BounceError::SampleRateAlreadyFixed { fixed: 48000, requested: 44100 })));
+
+        bounce(CompositionBuilder::new().build(), 0.1, 48000, path.to_str().unwrap()).unwrap();
+        assert_eq!(hound::WavReader::open(&path).unwrap().spec().sample_rate, 48000);
+        std::fs::remove_file(path).unwrap();
+    }
+}
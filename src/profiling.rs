@@ -0,0 +1,141 @@
+//! Optional timing of each sound and effect, enabled with the `profiling` feature.
+//! With the feature off, `time` and `indexed` just call the closure, so there is no overhead.
+//!
+//! Each timing is keyed by the component's path through the sounds and effects that contain it, e.g.
+//! `Sound::Composition/sounds[1]/Sound::Oscillator/effects[0]/Effect::Filter` for the first effect of the second sound
+//! of a composition.
+//! Two components of the same kind in different places get different keys, so the expensive one can be found.
+
+#[cfg(feature = "profiling")]
+use std::{cell::RefCell, cmp::Reverse, collections::HashMap, sync::{LazyLock, Mutex}, time::{Duration, Instant}};
+
+/// The total time spent in one component, and how many times it ran.
+#[cfg(feature = "profiling")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ComponentTiming {
+    pub total: Duration,
+    pub calls: u64,
+}
+
+#[cfg(feature = "profiling")]
+impl ComponentTiming {
+    pub fn average(&self) -> Duration {
+        if self.calls == 0 {
+            Duration::ZERO
+        } else {
+            self.total.div_f64(self.calls as f64)
+        }
+    }
+}
+
+#[cfg(feature = "profiling")]
+static TIMINGS: LazyLock<Mutex<HashMap<String, ComponentTiming>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(feature = "profiling")]
+thread_local! {
+    /// The path to the component running on this thread, one segment per enclosing component or index.
+    static PATH: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f` with `segment` added to the current path.
+#[cfg(feature = "profiling")]
+fn within<T>(segment: String, f: impl FnOnce() -> T) -> T {
+    PATH.with(|path| path.borrow_mut().push(segment));
+    let result = f();
+    PATH.with(|path| path.borrow_mut().pop());
+
+    result
+}
+
+/// Run `f`, adding the time it takes to the timing for the component `name` at the current path.
+#[cfg(feature = "profiling")]
+pub(crate) fn time<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let key = PATH.with(|path| {
+        let path = path.borrow();
+        if path.is_empty() { name.to_string() } else { format!("{}/{name}", path.join("/")) }
+    });
+
+    let start = Instant::now();
+    let result = within(name.to_string(), f);
+    let elapsed = start.elapsed();
+
+    let mut timings = TIMINGS.lock().unwrap();
+    let timing = timings.entry(key).or_default();
+    timing.total += elapsed;
+    timing.calls += 1;
+
+    result
+}
+
+/// Run `f` as item `index` of the component's list of sounds or effects called `list`, so each item is timed separately.
+#[cfg(feature = "profiling")]
+pub(crate) fn indexed<T>(list: &'static str, index: usize, f: impl FnOnce() -> T) -> T {
+    within(format!("{list}[{index}]"), f)
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub(crate) fn time<T>(_name: &'static str, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub(crate) fn indexed<T>(_list: &'static str, _index: usize, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// The timings accumulated so far, by component path, slowest first.
+#[cfg(feature = "profiling")]
+pub fn report() -> Vec<(String, ComponentTiming)> {
+    let mut report: Vec<_> = TIMINGS.lock().unwrap().iter().map(|(path, timing)| (path.clone(), *timing)).collect();
+    report.sort_by_key(|(_, timing)| Reverse(timing.total));
+
+    report
+}
+
+/// Clear all accumulated timings.
+#[cfg(feature = "profiling")]
+pub fn reset() {
+    TIMINGS.lock().unwrap().clear();
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use crate::{effects::{Effect, Filter}, oscillator::Oscillator, sound::{CompositionBuilder, Sound, SoundTrait}, Number};
+
+    #[test]
+    fn components_of_the_same_kind_are_timed_separately() {
+        crate::player::fix_sample_rate(48000);
+        let filter = || Effect::Filter(Filter::new_low_pass(Number::number(1000.0), Number::number(0.5), 2));
+        let mut oscillator = Oscillator::from_spec("saw C3").unwrap();
+        oscillator.add_effect(filter());
+        oscillator.add_effect(filter());
+        let mut composition = CompositionBuilder::new()
+            .sound(Sound::Oscillator(oscillator))
+            .sound(Sound::Oscillator(Oscillator::from_spec("sine C4").unwrap()))
+            .build();
+
+        let mut sound = Sound::Composition(composition.clone());
+        sound.next_grain();
+        composition.next_grain();
+
+        let paths: Vec<String> = report().into_iter().map(|(path, _)| path).collect();
+        for path in [
+            "Sound::Composition/sounds[0]/Sound::Oscillator/effects[0]/Effect::Filter",
+            "Sound::Composition/sounds[0]/Sound::Oscillator/effects[1]/Effect::Filter",
+            "Sound::Composition/sounds[1]/Sound::Oscillator",
+            "sounds[0]/Sound::Oscillator",
+        ] {
+            assert!(paths.iter().any(|recorded| recorded == path), "no timing for {path} in {paths:?}");
+        }
+    }
+
+    #[test]
+    fn average_does_not_truncate_the_call_count() {
+        // truncated to u32, this many calls would be 0
+        let timing = ComponentTiming { total: Duration::from_secs(10), calls: u64::from(u32::MAX) + 1 };
+        assert_eq!(timing.average(), Duration::from_nanos(2));
+    }
+}
@@ -1,70 +1,341 @@
 mod input;
 
-use crate::{effects::{Effect, EffectTrait}, player::SAMPLE_RATE, sound::{EffectInput, Grain, SoundTrait, SAMPLES_PER_GRAIN}};
+use crate::{effects::{chain_latency, Effect, EffectTrait, History, HISTORY_SAMPLES}, oscillator::ADSR, player::{render_samples, SAMPLE_RATE}, profiling, sound::{CompositionError, EffectContext, Grain, SoundTrait, SAMPLES_PER_GRAIN}};
 pub use input::{SampleInput, SampleInputAtTime, SampleInputIterator, SampleInputIteratorBuilder};
 use rodio::{Decoder, Source};
-use std::{f32::consts::PI, fs::File, io::BufReader};
+use std::{error::Error, f32::consts::PI, fmt::{self, Display, Formatter}, fs::File, io::BufReader};
+
+/// The shape of the window applied to each grain in granular processing.
+/// Smoother windows (Blackman, Hann) give fewer artifacts, while flatter ones (rectangular, triangular) smear transients less.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Window {
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+    Triangular,
+    Rectangular,
+}
 
-/// Returns a Hanning window of the given size.
-fn hanning_window(grain_size: usize) -> Vec<f32> {
-    (0..grain_size)
-        .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / (grain_size as f32 - 1.0)).cos()))
+/// Returns a window of the given kind and size.
+pub fn window(kind: Window, size: usize) -> Vec<f32> {
+    let last = (size as f32 - 1.0).max(1.0);
+
+    (0..size)
+        .map(|i| {
+            let x = i as f32 / last; // from 0 to 1 across the window
+            match kind {
+                Window::Hann => 0.5 * (1.0 - (2.0 * PI * x).cos()),
+                Window::Hamming => 0.54 - 0.46 * (2.0 * PI * x).cos(),
+                Window::Blackman => 0.42 - 0.5 * (2.0 * PI * x).cos() + 0.08 * (4.0 * PI * x).cos(),
+                Window::Triangular => 1.0 - (2.0 * x - 1.0).abs(),
+                Window::Rectangular => 1.0,
+            }
+        })
         .collect()
 }
 
-/// Merges a grain into a buffer, with the given overlap percentage. The grain is assumed to be windowed already.
-fn merge_grain_into_buffer(buffer: &[f32], grain: &[f32], overlap: f32) -> Vec<f32> {
-    let overlap_len = (overlap * SAMPLES_PER_GRAIN as f32) as usize;
-    let buffer_keep_len = buffer.len() - overlap_len;
-    let buffer_keep = &buffer[..buffer_keep_len];
-    let buffer_overlap = &buffer[buffer_keep_len..];
-    let grain_overlap = &grain[..overlap_len];
-    let grain_keep = &grain[overlap_len..];
+/// Quality settings for granular time-stretching.
+#[derive(Clone, Copy, Debug)]
+pub struct GrainOptions {
+    pub window: Window,
+    pub window_size: usize, // in samples
+    pub overlap: f32, // the fraction of each grain that overlaps the next, in [0, 1)
+}
 
-    let overlap: Vec<_> = buffer_overlap.iter().zip(grain_overlap.iter()).map(|(a, b)| a + b).collect();
-    let concat = [buffer_keep, &overlap, grain_keep].concat();
+impl Default for GrainOptions {
+    fn default() -> Self {
+        Self { window: Window::Hann, window_size: SAMPLES_PER_GRAIN, overlap: 0.5 }
+    }
+}
 
-    concat
+/// An error from time-stretching samples.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StretchError {
+    InvalidRatio(f32),
+    InvalidWindowSize(usize),
+    InvalidOverlap(f32),
 }
 
-/// Compresses a sample by the given speed factor. The speed must be between 0.0 and 1.0 (inclusive).
-fn compress(samples: &[f32], speed: f32) -> Vec<f32> {
-    assert!(speed > 0.0 && speed <= 1.0);
+impl Display for StretchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StretchError::InvalidRatio(ratio) => write!(f, "stretch ratio {ratio} must be positive and finite"),
+            StretchError::InvalidWindowSize(size) => write!(f, "window size {size} must be at least 2"),
+            StretchError::InvalidOverlap(overlap) => write!(f, "overlap {overlap} must be in [0, 1)"),
+        }
+    }
+}
 
-    let grains: Vec<_> = samples.chunks(SAMPLES_PER_GRAIN).collect();
-    let standard_window = hanning_window(SAMPLES_PER_GRAIN);
-    let last_grain_len = grains.last().unwrap().len();
-    let final_window = hanning_window(last_grain_len);
+impl Error for StretchError {}
 
-    let mut grains: Vec<Vec<_>> = grains[..grains.len() - 1]
-        .iter()
-        .map(|grain| grain
-            .iter()
-            .zip(standard_window.iter())
-            .map(|(a, b)| a * b)
-            .collect())
-        .collect();
-    let mut final_grain_windowed: Vec<_> = grains
-        .last()
-        .unwrap()
-        .iter()
-        .zip(final_window.iter())
-        .map(|(a, b)| a * b)
+/// An error from loading samples from a file.
+#[derive(Debug)]
+pub enum LoadError {
+    UnsupportedFileType(String),
+    Io(std::io::Error),
+    Wav(hound::Error),
+    Mp3(rodio::decoder::DecoderError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedFileType(path) => write!(f, "unsupported file type: {path}"),
+            LoadError::Io(err) => write!(f, "couldn't open the file: {err}"),
+            LoadError::Wav(err) => write!(f, "couldn't read the .wav file: {err}"),
+            LoadError::Mp3(err) => write!(f, "couldn't decode the .mp3 file: {err}"),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Adds a grain into a buffer starting at `position`, growing the buffer if needed. The grain is assumed to be windowed already.
+fn merge_grain_into_buffer(buffer: &mut Vec<f32>, grain: &[f32], position: usize) {
+    let end = position + grain.len();
+    if buffer.len() < end {
+        buffer.resize(end, 0.0);
+    }
+
+    for (buffer_sample, grain_sample) in buffer[position..end].iter_mut().zip(grain) {
+        *buffer_sample += grain_sample;
+    }
+}
+
+/// Changes the length of samples without changing their pitch, by overlap-adding windowed grains.
+/// `ratio` is the output length divided by the input length, so below 1 shortens and above 1 lengthens.
+/// Grains are written every `window_size * (1 - overlap)` samples and read every `1 / ratio` times that.
+/// The output is divided by the sum of the overlapping windows, so constant-amplitude input gives
+/// constant-amplitude output for any window and overlap.
+pub fn time_stretch(samples: &[f32], ratio: f32, options: GrainOptions) -> Result<Vec<f32>, StretchError> {
+    check_stretch(ratio, options)?;
+
+    let output_len = (samples.len() as f32 * ratio).round() as usize;
+    let synthesis_hop = synthesis_hop(options);
+    let analysis_hop = synthesis_hop as f32 / ratio;
+
+    Ok(overlap_add(samples, output_len, options, |position| {
+        ((position / synthesis_hop) as f32 * analysis_hop) as usize
+    }))
+}
+
+/// How much the RMS level of a frame of `ONSET_FRAME` samples must rise over the previous frame's for an onset, i.e. about 6 dB.
+const ONSET_RISE: f32 = 2.0;
+/// Frames quieter than this fraction of the loudest frame's level are never onsets, so noise doesn't trigger them.
+const ONSET_FLOOR: f32 = 0.1;
+const ONSET_FRAME: usize = 256;
+
+/// The positions where the level of the samples suddenly rises, e.g. drum hits.
+fn detect_onsets(samples: &[f32]) -> Vec<usize> {
+    let levels: Vec<f32> = samples
+        .chunks(ONSET_FRAME)
+        .map(|frame| (frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32).sqrt())
         .collect();
-    let final_grain_padding = SAMPLES_PER_GRAIN - last_grain_len;
-    final_grain_windowed.extend(vec![0.0; final_grain_padding]);
-    grains.push(final_grain_windowed);
+    let floor = ONSET_FLOOR * levels.iter().copied().fold(0.0, f32::max);
+
+    let mut onsets = Vec::new();
+    let mut previous = 0.0f32;
+    for (i, level) in levels.iter().enumerate() {
+        if *level > floor && *level > ONSET_RISE * previous.max(floor) {
+            onsets.push(i * ONSET_FRAME);
+        }
+        previous = *level;
+    }
+
+    onsets
+}
+
+/// Like `time_stretch`, but leaves the onsets in the samples (sudden rises in level, e.g. drum hits) at their original
+/// speed and changes the length of the rest to make up for them, so shortening a drum loop doesn't smear its hits.
+///
+/// From a window before each onset to a window after it, grains are read at the same rate they are written, so they
+/// overlap-add back into the original samples. If the onsets leave too little else to stretch into `ratio`,
+/// this stretches evenly like `time_stretch`.
+pub fn time_stretch_preserving_transients(samples: &[f32], ratio: f32, options: GrainOptions) -> Result<Vec<f32>, StretchError> {
+    check_stretch(ratio, options)?;
+
+    // merge the regions around each onset where they overlap
+    let mut kept: Vec<(usize, usize)> = Vec::new();
+    for onset in detect_onsets(samples) {
+        let start = onset.saturating_sub(options.window_size);
+        let end = (onset + options.window_size).min(samples.len());
+        match kept.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => kept.push((start, end)),
+        }
+    }
+
+    let output_len = (samples.len() as f32 * ratio).round() as usize;
+    let kept_len: usize = kept.iter().map(|(start, end)| end - start).sum();
+    let rest_len = samples.len() - kept_len;
+    if kept.is_empty() || rest_len == 0 || output_len <= kept_len {
+        return time_stretch(samples, ratio, options);
+    }
+    let rest_ratio = (output_len - kept_len) as f32 / rest_len as f32;
+
+    // map each output position back to the input, at full speed in kept regions and `rest_ratio` elsewhere
+    let mut map = Vec::new(); // (output start, input start, ratio) of each segment
+    let mut input = 0;
+    let mut output = 0.0;
+    for (start, end) in kept.into_iter().chain(std::iter::once((samples.len(), samples.len()))) {
+        for (segment_start, segment_end, segment_ratio) in [(input, start, rest_ratio), (start, end, 1.0)] {
+            if segment_end > segment_start {
+                map.push((output, segment_start, segment_ratio));
+                output += (segment_end - segment_start) as f32 * segment_ratio;
+            }
+        }
+        input = end;
+    }
+
+    Ok(overlap_add(samples, output_len, options, |position| {
+        let position = position as f32;
+        let (output_start, input_start, segment_ratio) = map
+            .iter()
+            .rev()
+            .find(|(output_start, _, _)| *output_start <= position)
+            .copied()
+            .unwrap_or((0.0, 0, 1.0));
+
+        input_start + ((position - output_start) / segment_ratio) as usize
+    }))
+}
+
+fn check_stretch(ratio: f32, options: GrainOptions) -> Result<(), StretchError> {
+    if !(ratio.is_finite() && ratio > 0.0) {
+        return Err(StretchError::InvalidRatio(ratio));
+    }
+    if options.window_size < 2 {
+        return Err(StretchError::InvalidWindowSize(options.window_size));
+    }
+    if !(0.0..1.0).contains(&options.overlap) {
+        return Err(StretchError::InvalidOverlap(options.overlap));
+    }
+
+    Ok(())
+}
+
+fn synthesis_hop(options: GrainOptions) -> usize {
+    ((options.window_size as f32 * (1.0 - options.overlap)) as usize).max(1)
+}
+
+/// Overlap-adds windowed grains every synthesis hop into `output_len` samples,
+/// reading the grain written at each output position from the input position `read_start` gives for it.
+fn overlap_add(samples: &[f32], output_len: usize, options: GrainOptions, read_start: impl Fn(usize) -> usize) -> Vec<f32> {
+    let window = window(options.window, options.window_size);
+    let synthesis_hop = synthesis_hop(options);
+
+    let mut buffer = Vec::with_capacity(output_len + options.window_size);
+    let mut window_sum = Vec::with_capacity(output_len + options.window_size);
+    let mut grain = vec![0.0; options.window_size];
+    let mut grain_index = 0;
+    while grain_index * synthesis_hop < output_len {
+        let read_start = read_start(grain_index * synthesis_hop);
+        for (i, sample) in grain.iter_mut().enumerate() {
+            *sample = samples.get(read_start + i).copied().unwrap_or(0.0) * window[i];
+        }
 
-    let mut buffer = grains.first().unwrap().clone();
-    for grain in grains.iter().skip(1) {
-        buffer = merge_grain_into_buffer(&buffer, grain, speed);
+        merge_grain_into_buffer(&mut buffer, &grain, grain_index * synthesis_hop);
+        merge_grain_into_buffer(&mut window_sum, &window, grain_index * synthesis_hop);
+        grain_index += 1;
+    }
+    buffer.resize(output_len, 0.0);
+
+    // undo the gain of the overlapping windows. where they are near zero, so is the signal
+    for (sample, weight) in buffer.iter_mut().zip(window_sum) {
+        if weight > 1e-3 {
+            *sample /= weight;
+        }
     }
-    buffer.truncate(buffer.len() - final_grain_padding);
 
     buffer
 }
 
-fn normalize_sample_length(samples: Vec<f32>, target_length: usize) -> Vec<f32> {
+/// Converts samples recorded at `from_rate` to `to_rate`, keeping their pitch and duration, by linear interpolation.
+/// Unlike `time_stretch`, this changes how many samples make up each cycle of a tone, so it is what playing a file on a
+/// device with a different sample rate needs. Downsampling doesn't filter first, so content above the new Nyquist
+/// frequency aliases.
+pub fn resample(samples: &[f32], from_rate: usize, to_rate: usize) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let step = from_rate as f64 / to_rate as f64;
+    let output_len = (samples.len() as f64 / step).round() as usize;
+
+    (0..output_len)
+        .map(|i| {
+            let position = i as f64 * step;
+            let index = position.floor() as usize;
+            let fraction = (position - index as f64) as f32;
+            let current = samples[index.min(samples.len() - 1)];
+            let next = samples[(index + 1).min(samples.len() - 1)];
+
+            current + fraction * (next - current)
+        })
+        .collect()
+}
+
+/// How to convert a sample between sample rates. Either way, a sample too long for the beat is shortened with
+/// `time_stretch`, which keeps its pitch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Convert sample rates by linear interpolation. Cheap enough for long samples, but high frequencies can alias.
+    #[default]
+    Fast,
+    /// Convert sample rates with band-limited windowed-sinc interpolation, so nothing aliases or dulls.
+    /// Much slower than `Fast`, though only run when the sample rate changes.
+    Sinc,
+}
+
+/// The number of zero crossings of the sinc kernel on each side of a sample. More is sharper but slower.
+const SINC_ZERO_CROSSINGS: f32 = 16.0;
+
+/// Resamples to exactly `output_len` samples with a Blackman-windowed sinc kernel.
+/// When shortening, the kernel is widened to low-pass below the new Nyquist frequency first.
+fn sinc_resample_to_length(samples: &[f32], output_len: usize) -> Vec<f32> {
+    if samples.is_empty() || output_len == 0 {
+        return vec![0.0; output_len];
+    }
+
+    let step = samples.len() as f64 / output_len as f64;
+    let cutoff = (1.0 / step).min(1.0) as f32; // as a fraction of the input's Nyquist frequency
+    let half_width = SINC_ZERO_CROSSINGS / cutoff; // in input samples
+
+    (0..output_len)
+        .map(|i| {
+            let centre = i as f64 * step;
+            let first = (centre - half_width as f64).ceil().max(0.0) as usize;
+            let last = ((centre + half_width as f64).floor() as usize).min(samples.len() - 1);
+
+            (first..=last)
+                .map(|j| {
+                    let x = (j as f64 - centre) as f32;
+                    let t = x / half_width; // from -1 to 1 across the kernel
+                    let blackman = 0.42 + 0.5 * (PI * t).cos() + 0.08 * (2.0 * PI * t).cos();
+                    let sinc = if x == 0.0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+
+                    samples[j] * cutoff * sinc * blackman
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Like `resample`, but with windowed-sinc interpolation, which filters out content above the new Nyquist frequency
+/// when downsampling instead of letting it alias. See `ResampleQuality::Sinc`.
+pub fn sinc_resample(samples: &[f32], from_rate: usize, to_rate: usize) -> Vec<f32> {
+    if from_rate == to_rate {
+        return samples.to_vec();
+    }
+    let output_len = (samples.len() as f64 * to_rate as f64 / from_rate as f64).round() as usize;
+
+    sinc_resample_to_length(samples, output_len)
+}
+
+/// Fits samples to exactly `target_length` to match a beat, padding with silence if they are short.
+/// Long samples are time-stretched, keeping their pitch, and keeping onsets intact if `preserve_transients` is set.
+fn normalize_sample_length(samples: Vec<f32>, target_length: usize, preserve_transients: bool) -> Vec<f32> {
     if samples.len() == target_length {
         samples
     } else if samples.len() < target_length {
@@ -74,8 +345,12 @@ fn normalize_sample_length(samples: Vec<f32>, target_length: usize) -> Vec<f32>
         result
     } else {
         // resample to exact target length
-        let speed = target_length as f32 / samples.len() as f32;
-        let compressed = compress(&samples, speed);
+        let ratio = target_length as f32 / samples.len() as f32;
+        let compressed = if preserve_transients {
+            time_stretch_preserving_transients(&samples, ratio, GrainOptions::default()).unwrap()
+        } else {
+            time_stretch(&samples, ratio, GrainOptions::default()).unwrap()
+        };
 
         if compressed.len() > target_length {
             compressed[0..target_length].to_vec()
@@ -92,12 +367,16 @@ fn normalize_sample_length(samples: Vec<f32>, target_length: usize) -> Vec<f32>
 #[derive(Clone, Debug)]
 pub struct Sample {
     samples: Vec<f32>,
+    sample_rate: usize,
     secs_per_beat: f32,
     index: usize,
     pub effects: Vec<Effect>,
     secs_since_start: f32,
     inputs: SampleInputIterator,
     play: bool,
+    history: History,
+    adsr: Option<ADSR>,
+    resample_quality: ResampleQuality,
 }
 
 impl Sample {
@@ -106,18 +385,138 @@ impl Sample {
         sample_rate: usize,
         secs_per_beat: f32,
         inputs: SampleInputIterator,
+    ) -> Self {
+        Self::new_with_quality(samples, sample_rate, secs_per_beat, inputs, ResampleQuality::default(), false)
+    }
+
+    fn new_with_quality(
+        samples: Vec<f32>,
+        sample_rate: usize,
+        secs_per_beat: f32,
+        inputs: SampleInputIterator,
+        resample_quality: ResampleQuality,
+        preserve_transients: bool,
     ) -> Self {
         let target_samples = (sample_rate as f32 * secs_per_beat) as usize;
-        let samples = normalize_sample_length(samples, target_samples);
+        let samples = normalize_sample_length(samples, target_samples, preserve_transients);
 
         Self {
             samples,
+            sample_rate,
             secs_per_beat,
             index: 0,
             effects: Vec::new(),
             secs_since_start: 0.0,
             inputs,
             play: false,
+            history: History::default(),
+            adsr: None,
+            resample_quality,
+        }
+    }
+
+    /// Pre-render `duration_secs` of a sound into a sample, so it can be replayed cheaply.
+    /// The sample is triggered once at the start; use `set_inputs` to trigger it differently.
+    pub fn bounce(sound: &mut dyn SoundTrait, duration_secs: f32, sample_rate: usize) -> Self {
+        sound.update_sample_rate(sample_rate);
+        let num_samples = (duration_secs * sample_rate as f32) as usize;
+        let samples = render_samples(sound, num_samples);
+
+        let inputs = SampleInputIteratorBuilder::new()
+            .input(SampleInputAtTime {
+                input: SampleInput::Trigger,
+                time: 0.0,
+            })
+            .build();
+
+        Self::new(samples, sample_rate, duration_secs, inputs)
+    }
+
+    /// Whether the sample has been triggered and hasn't reached its end yet.
+    pub fn is_playing(&self) -> bool {
+        self.play
+    }
+
+    /// How long the sample plays for each time it is triggered.
+    pub fn duration_secs(&self) -> f32 {
+        self.samples.len() as f32 / self.sample_rate as f32
+    }
+
+    pub fn set_inputs(&mut self, inputs: SampleInputIterator) {
+        self.inputs = inputs;
+    }
+
+    /// Snap the requested loop points to the nearest zero crossings, so the loop doesn't click.
+    /// The end point is snapped to a crossing going in the same direction as the start point's,
+    /// so the waveform continues smoothly across the loop. Points with no suitable crossing are returned unchanged.
+    pub fn find_loop_points(&self, near_start_secs: f32, near_end_secs: f32) -> (f32, f32) {
+        let samples_per_sec = self.samples.len() as f32 / self.secs_per_beat;
+        let to_index = |secs: f32| ((secs * samples_per_sec) as usize).min(self.samples.len().saturating_sub(1));
+        let to_secs = |index: usize| index as f32 / samples_per_sec;
+
+        let crossings: Vec<(usize, bool)> = self.samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| (pair[0] <= 0.0) != (pair[1] <= 0.0))
+            .map(|(i, pair)| (i + 1, pair[1] > pair[0])) // (index, rising)
+            .collect();
+
+        let nearest = |target: usize, rising: Option<bool>| crossings
+            .iter()
+            .filter(|(_, crossing_rising)| rising.is_none_or(|rising| rising == *crossing_rising))
+            .min_by_key(|(index, _)| index.abs_diff(target))
+            .copied();
+
+        let Some((start, rising)) = nearest(to_index(near_start_secs), None) else {
+            return (near_start_secs, near_end_secs);
+        };
+        let end = nearest(to_index(near_end_secs), Some(rising)).map_or(near_end_secs, |(index, _)| to_secs(index));
+
+        (to_secs(start), end)
+    }
+
+    /// Insert an effect at `index`, before the effect currently there. Panics if `index` is past the end.
+    pub fn insert_effect(&mut self, index: usize, effect: Effect) {
+        self.effects.insert(index, effect);
+    }
+
+    pub fn remove_effect(&mut self, index: usize) -> Option<Effect> {
+        (index < self.effects.len()).then(|| self.effects.remove(index))
+    }
+
+    pub fn effect_count(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub(crate) fn latency_samples(&self) -> usize {
+        chain_latency(&self.effects)
+    }
+
+    pub(crate) fn collect_errors(&self, errors: &mut Vec<CompositionError>) {
+        if self.samples.is_empty() {
+            errors.push(CompositionError::EmptySample);
+        }
+        if !(self.secs_per_beat.is_finite() && self.secs_per_beat > 0.0) {
+            errors.push(CompositionError::InvalidTempo(self.secs_per_beat));
+        }
+
+        for effect in &self.effects {
+            effect.collect_errors(Some(self.secs_per_beat), errors);
+        }
+    }
+
+    /// The envelope's amplitude at the current playback position. The release is timed to end with the sample.
+    fn envelope_amplitude(&self) -> f32 {
+        let Some(adsr) = &self.adsr else {
+            return 1.0;
+        };
+
+        let secs = self.index as f32 / self.sample_rate as f32;
+        let release_start = (self.duration_secs() - adsr.release_duration).max(0.0);
+        if secs < release_start {
+            adsr.held_amplitude(secs)
+        } else {
+            adsr.release_amplitude(adsr.held_amplitude(release_start), secs - release_start).unwrap_or(0.0)
         }
     }
 
@@ -142,6 +541,11 @@ impl SoundTrait for Sample {
         Some(self.secs_per_beat)
     }
 
+    /// When the last trigger finishes playing, or `None` if the triggers repeat forever.
+    fn length_secs(&self) -> Option<f32> {
+        self.inputs.last_time().map(|last_time| last_time + self.duration_secs())
+    }
+
     fn next_sample(&mut self) -> f32 {
         self.secs_since_start += 1.0 / *SAMPLE_RATE as f32;
 
@@ -155,7 +559,7 @@ impl SoundTrait for Sample {
             return 0.0;
         }
 
-        self.samples[self.index]
+        self.samples[self.index] * self.envelope_amplitude()
     }
 
     fn next_grain(&mut self) -> Grain {
@@ -166,27 +570,45 @@ impl SoundTrait for Sample {
             *sample = self.next_sample();
         }
 
-        let time_since_start_of_beat = self.index as f32 / self.samples.len() as f32;
-        for effect in &mut self.effects {
-            let input = EffectInput {
-                grain,
-                time_since_start_of_beat,
-            };
-            let output = effect.apply(input);
+        let context = EffectContext {
+            sample_rate: *SAMPLE_RATE,
+            secs_per_beat: Some(self.secs_per_beat),
+            time_since_start: self.secs_since_start,
+            history: &self.history,
+        };
+        let dry = grain;
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            let output = profiling::indexed("effects", i, || effect.apply(grain, &context));
             grain = output.grain;
         }
+        self.history.push_grain(&dry);
 
         grain
     }
 
     fn update_sample_rate(&mut self, sample_rate: usize) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+
+        // the samples already fit the beat, so they only need converting, not fitting again
+        let mut samples = match self.resample_quality {
+            ResampleQuality::Fast => resample(&self.samples, self.sample_rate, sample_rate),
+            ResampleQuality::Sinc => sinc_resample(&self.samples, self.sample_rate, sample_rate),
+        };
+        // rounding can leave the length a sample off the beat
         let target_samples = (sample_rate as f32 * self.secs_per_beat) as usize;
-        self.samples = normalize_sample_length(std::mem::take(&mut self.samples), target_samples);
+        samples.resize(target_samples, 0.0);
+
+        self.index = self.index * sample_rate / self.sample_rate;
+        self.samples = samples;
+        self.sample_rate = sample_rate;
     }
 
     fn clone_box(&self) -> Box<dyn SoundTrait> {
         Box::new(Sample {
             samples: self.samples.clone(),
+            sample_rate: self.sample_rate,
             secs_per_beat: self.secs_per_beat,
             index: self.index,
             // effects: self.effects.iter().map(|e| e.clone_box()).collect(),
@@ -194,6 +616,9 @@ impl SoundTrait for Sample {
             secs_since_start: self.secs_since_start,
             inputs: self.inputs.clone(),
             play: self.play,
+            history: self.history.clone(),
+            adsr: self.adsr.clone(),
+            resample_quality: self.resample_quality,
         })
     }
 
@@ -202,19 +627,33 @@ impl SoundTrait for Sample {
     }
 }
 
+/// Slices samples to the region between `start_secs` and `end_secs` (or the end, if `None`).
+fn trim_samples(samples: Vec<f32>, sample_rate: usize, start_secs: f32, end_secs: Option<f32>) -> Vec<f32> {
+    let duration = samples.len() as f32 / sample_rate as f32;
+    let end_secs = end_secs.unwrap_or(duration);
+    assert!(start_secs >= 0.0, "trim start {start_secs}s is negative");
+    assert!(end_secs <= duration, "trim end {end_secs}s is past the end of the sample ({duration}s)");
+    assert!(start_secs < end_secs, "trim start {start_secs}s is not before trim end {end_secs}s");
+
+    let start = (start_secs * sample_rate as f32) as usize;
+    let end = ((end_secs * sample_rate as f32) as usize).min(samples.len());
+
+    samples[start..end].to_vec()
+}
+
 // returns (samples, sample rate)
-fn load_sample_wav(path: &str) -> (Vec<f32>, usize) {
-    let mut reader = hound::WavReader::open(path).unwrap();
+fn load_sample_wav(path: &str) -> Result<(Vec<f32>, usize), LoadError> {
+    let mut reader = hound::WavReader::open(path).map_err(LoadError::Wav)?;
     let sample_rate = reader.spec().sample_rate;
-    let samples: Vec<i32> = reader.samples::<i32>().map(|s| s.unwrap()).collect();
+    let samples: Vec<i32> = reader.samples::<i32>().collect::<Result<_, _>>().map_err(LoadError::Wav)?;
 
-    (samples.iter().map(|s| *s as f32 / i32::MAX as f32).collect(), sample_rate as usize)
+    Ok((samples.iter().map(|s| *s as f32 / i32::MAX as f32).collect(), sample_rate as usize))
 }
 
 // returns (samples, sample rate)
-fn load_sample_mp3(path: &str) -> (Vec<f32>, usize) {
-    let file = File::open(path).unwrap();
-    let source = Decoder::new(BufReader::new(file)).unwrap();
+fn load_sample_mp3(path: &str) -> Result<(Vec<f32>, usize), LoadError> {
+    let file = File::open(path).map_err(LoadError::Io)?;
+    let source = Decoder::new(BufReader::new(file)).map_err(LoadError::Mp3)?;
     let sample_rate = source.sample_rate();
 
     let samples: Vec<f32> = source
@@ -222,7 +661,23 @@ fn load_sample_mp3(path: &str) -> (Vec<f32>, usize) {
         .map(|sample| sample / i16::MAX as f32)
         .collect();
     
-    (samples, sample_rate as usize)
+    Ok((samples, sample_rate as usize))
+}
+
+/// Load a .wav or .mp3 file, returning its samples and sample rate.
+pub(crate) fn try_load_samples_from_file(path: &str) -> Result<(Vec<f32>, usize), LoadError> {
+    if path.ends_with(".wav") {
+        load_sample_wav(path)
+    } else if path.ends_with(".mp3") {
+        load_sample_mp3(path)
+    } else {
+        Err(LoadError::UnsupportedFileType(path.to_string()))
+    }
+}
+
+/// Load a .wav or .mp3 file, returning its samples and sample rate. Panics if it can't be loaded.
+pub(crate) fn load_samples_from_file(path: &str) -> (Vec<f32>, usize) {
+    try_load_samples_from_file(path).unwrap_or_else(|err| panic!("couldn't load {path}: {err}"))
 }
 
 pub struct SampleBuilder {
@@ -231,6 +686,11 @@ pub struct SampleBuilder {
     secs_per_beat: Option<f32>,
     effects: Vec<Effect>,
     inputs: Option<SampleInputIterator>,
+    trim: Option<(f32, Option<f32>)>,
+    adsr: Option<ADSR>,
+    history_samples: usize,
+    resample_quality: ResampleQuality,
+    preserve_transients: bool,
 }
 
 impl SampleBuilder {
@@ -241,9 +701,21 @@ impl SampleBuilder {
             secs_per_beat: None,
             effects: Vec::new(),
             inputs: None,
+            trim: None,
+            adsr: None,
+            history_samples: HISTORY_SAMPLES,
+            resample_quality: ResampleQuality::default(),
+            preserve_transients: false,
         }
     }
 
+    /// Keep only the part of the samples between `start_secs` and `end_secs` (or the end, if `None`).
+    /// This is applied before the samples are fitted to the beat.
+    pub fn trim(mut self, start_secs: f32, end_secs: Option<f32>) -> Self {
+        self.trim = Some((start_secs, end_secs));
+        self
+    }
+
     pub fn samples(mut self, samples: Vec<f32>) -> Self {
         self.samples = Some(samples);
         self
@@ -255,13 +727,7 @@ impl SampleBuilder {
     }
 
     pub fn samples_from_file(mut self, path: &str) -> Self {
-        let (samples, sample_rate) = if path.ends_with(".wav") {
-            load_sample_wav(path)
-        } else if path.ends_with(".mp3") {
-            load_sample_mp3(path)
-        } else {
-            panic!("Unsupported file type: {}", path);
-        };
+        let (samples, sample_rate) = load_samples_from_file(path);
 
         self.samples = Some(samples);
         self.sample_rate = Some(sample_rate);
@@ -278,18 +744,50 @@ impl SampleBuilder {
         self
     }
 
+    /// Shape each triggered playback with an envelope, starting at the trigger.
+    /// Samples have no release input, so the release is timed to finish at the end of the sample.
+    pub fn adsr(mut self, adsr: ADSR) -> Self {
+        self.adsr = Some(adsr);
+        self
+    }
+
+    /// How the sample is converted between sample rates. `ResampleQuality::Fast` by default.
+    pub fn resample_quality(mut self, resample_quality: ResampleQuality) -> Self {
+        self.resample_quality = resample_quality;
+        self
+    }
+
+    /// Keep onsets such as drum hits at their original speed when shortening the sample to fit the beat,
+    /// compressing only the audio between them. See `time_stretch_preserving_transients`. Off by default.
+    pub fn preserve_transients(mut self, preserve_transients: bool) -> Self {
+        self.preserve_transients = preserve_transients;
+        self
+    }
+
+    /// How many of the sample's recent dry samples to keep for its effects to look back on. See `History`.
+    pub fn history_samples(mut self, samples: usize) -> Self {
+        self.history_samples = samples;
+        self
+    }
+
     pub fn inputs(mut self, inputs: SampleInputIterator) -> Self {
         self.inputs = Some(inputs);
         self
     }
 
     pub fn build(self) -> Sample {
-        let samples = self.samples.unwrap();
+        let mut samples = self.samples.unwrap();
         let sample_rate = self.sample_rate.unwrap();
+        if let Some((start_secs, end_secs)) = self.trim {
+            samples = trim_samples(samples, sample_rate, start_secs, end_secs);
+        }
+
         let secs_per_beat = self.secs_per_beat.unwrap();
         let inputs = self.inputs.unwrap();
 
-        let mut sample = Sample::new(samples, sample_rate, secs_per_beat, inputs);
+        let mut sample = Sample::new_with_quality(samples, sample_rate, secs_per_beat, inputs, self.resample_quality, self.preserve_transients);
+        sample.adsr = self.adsr;
+        sample.history = History::new(self.history_samples);
         for effect in self.effects {
             sample.add_effect(effect);
         }
@@ -297,3 +795,37 @@ impl SampleBuilder {
         sample
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The frequency in `candidates` with the most energy in `samples`.
+    fn strongest_frequency(samples: &[f32], sample_rate: usize, candidates: impl Iterator<Item = f32>) -> f32 {
+        let energy = |frequency: f32| {
+            let (re, im) = samples.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, sample)| {
+                let angle = 2.0 * PI * frequency * i as f32 / sample_rate as f32;
+                (re + sample * angle.cos(), im + sample * angle.sin())
+            });
+            re * re + im * im
+        };
+
+        candidates.max_by(|a, b| energy(*a).total_cmp(&energy(*b))).unwrap()
+    }
+
+    #[test]
+    fn fitting_a_long_sample_keeps_its_pitch() {
+        let sample_rate = 48000;
+        let frequency = 440.0;
+        let samples: Vec<f32> = (0..3 * sample_rate / 2)
+            .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect();
+
+        let fitted = normalize_sample_length(samples, sample_rate, false);
+        assert_eq!(fitted.len(), sample_rate);
+
+        // shortening by resampling would have raised it a fifth, to 660 Hz; overlap-add only blurs it a little
+        let measured = strongest_frequency(&fitted, sample_rate, (400..=700).step_by(5).map(|frequency| frequency as f32));
+        assert!((measured - frequency).abs() < 0.1 * frequency, "measured {measured} Hz");
+    }
+}
@@ -39,6 +39,14 @@ impl SampleInputIterator {
         }
     }
 
+    /// The time of the last input, or `None` if the inputs repeat forever.
+    pub fn last_time(&self) -> Option<f32> {
+        match self.repeat_delay {
+            Some(_) => None,
+            None => self.inputs.last().map(|input| input.time),
+        }
+    }
+
     pub fn next(&mut self, secs_since_start: f32) -> Option<SampleInputAtTime> {
         if self.index >= self.inputs.len() {
             return None;
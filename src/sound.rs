@@ -1,14 +1,29 @@
-use crate::{effects::{Effect, EffectTrait}, oscillator::Oscillator, player::SAMPLE_RATE, sample::Sample};
+mod live_input;
+mod metronome;
+mod tempo;
+
+use crate::{effects::{chain_latency, Effect, EffectTrait, History, HISTORY_SAMPLES}, oscillator::{BeatClock, Oscillator, OscillatorState}, player::SAMPLE_RATE, profiling, sample::Sample, Number};
+pub use live_input::InputSound;
+pub use metronome::Metronome;
+pub use tempo::TempoAutomation;
+use std::{error::Error, f32::consts::PI, fmt::{self, Display, Formatter}, ops::RangeInclusive};
 
 pub const SAMPLES_PER_GRAIN: usize = 512;
+/// How many times further than its neighbouring steps a grain boundary must jump for `grain_smoothing` to treat it as a click.
+const DISCONTINUITY_RATIO: f32 = 2.0;
 
 pub type Grain = [f32; SAMPLES_PER_GRAIN];
 
-/// The data passed to an effect.
-#[derive(Clone)]
-pub struct EffectInput {
-    pub grain: Grain,
-    pub time_since_start_of_beat: f32, // in seconds
+/// The context passed to an effect alongside each grain.
+#[derive(Clone, Debug)]
+pub struct EffectContext<'a> {
+    pub sample_rate: usize,
+    pub secs_per_beat: Option<f32>,
+    /// Seconds since the sound started, measured at the end of the grain.
+    /// This is the same for every kind of sound, so effects behave identically on each.
+    pub time_since_start: f32,
+    /// The owning sound's dry signal before this grain.
+    pub history: &'a History,
 }
 
 pub trait SoundTrait: Send + Sync {
@@ -18,24 +33,304 @@ pub trait SoundTrait: Send + Sync {
     fn update_sample_rate(&mut self, sample_rate: usize);
     fn clone_box(&self) -> Box<dyn SoundTrait>;
     fn secs_per_beat(&self) -> Option<f32>;
+    /// How long the sound plays for, in seconds, or `None` if it never ends.
+    /// Effect tails, like delay echoes, are not included.
+    fn length_secs(&self) -> Option<f32> {
+        None
+    }
+}
+
+impl SoundTrait for Box<dyn SoundTrait> {
+    fn next_sample(&mut self) -> f32 {
+        (**self).next_sample()
+    }
+
+    fn next_grain(&mut self) -> Grain {
+        (**self).next_grain()
+    }
+
+    fn add_effect(&mut self, effect: Effect) {
+        (**self).add_effect(effect);
+    }
+
+    fn update_sample_rate(&mut self, sample_rate: usize) {
+        (**self).update_sample_rate(sample_rate);
+    }
+
+    fn clone_box(&self) -> Box<dyn SoundTrait> {
+        (**self).clone_box()
+    }
+
+    fn secs_per_beat(&self) -> Option<f32> {
+        (**self).secs_per_beat()
+    }
+
+    fn length_secs(&self) -> Option<f32> {
+        (**self).length_secs()
+    }
+}
+
+/// A broken configuration found by `Composition::validate`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompositionError {
+    OutOfRange { parameter: &'static str, value: f32 },
+    DelayTooLong(f32), // in seconds
+    EmptySample,
+    InvalidTempo(f32), // in seconds per beat
+    MissingTempo { parameter: &'static str }, // a duration in beats where no tempo is given
+}
+
+impl Display for CompositionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CompositionError::OutOfRange { parameter, value } => write!(f, "{parameter} is out of range: {value}"),
+            CompositionError::DelayTooLong(delay) => write!(f, "delay of {delay} seconds is too long"),
+            CompositionError::EmptySample => write!(f, "sample has no audio"),
+            CompositionError::InvalidTempo(secs_per_beat) => write!(f, "invalid tempo of {secs_per_beat} seconds per beat"),
+            CompositionError::MissingTempo { parameter } => write!(f, "{parameter} is in beats, but there is no tempo"),
+        }
+    }
+}
+
+impl Error for CompositionError {}
+
+/// Record an error if `value` is outside `range`.
+pub(crate) fn check_range(parameter: &'static str, value: f32, range: RangeInclusive<f32>, errors: &mut Vec<CompositionError>) {
+    if !range.contains(&value) {
+        errors.push(CompositionError::OutOfRange { parameter, value });
+    }
+}
+
+/// Record an error if `number` is constant and outside `range`. Modulated numbers can't be checked ahead of time.
+pub(crate) fn check_number_range(parameter: &'static str, number: &Number, range: RangeInclusive<f32>, errors: &mut Vec<CompositionError>) {
+    if let Some(value) = number.constant_value() {
+        check_range(parameter, value, range, errors);
+    }
+}
+
+/// Identifies an aux bus within a `Composition`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusId(usize);
+
+/// Identifies a sound within a `Composition`. Ids are not reused after a sound is removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SoundId(usize);
+
+/// A portion of one sound's signal sent to an aux bus.
+#[derive(Clone, Debug)]
+struct AuxSend {
+    sound: SoundId,
+    bus: BusId,
+    amount: f32,
 }
 
 #[derive(Clone, Debug)]
 pub struct Composition {
     sounds: Vec<Sound>,
+    sound_ids: Vec<SoundId>, // the id of each sound in `sounds`
+    next_sound_id: usize,
     effects: Vec<Effect>,
     secs_since_start: f32,
+    secs_per_beat: Option<f32>,
+    tempo_automation: Option<TempoAutomation>,
+    aux_buses: Vec<Effect>,
+    aux_sends: Vec<AuxSend>,
+    grain_smoothing: usize, // in samples
+    previous_tail: Option<(f32, f32)>, // the last sample of the previous grain, and its slope
+    history: History,
+    beat_clock: Option<BeatClock>,
 }
 
 impl Composition {
+    /// Sounds are given ids in order, starting from 0.
     pub fn new(sounds: Vec<Sound>, effects: Vec<Effect>) -> Self {
-        Self { sounds, effects, secs_since_start: 0.0 }
+        Self {
+            sound_ids: (0..sounds.len()).map(SoundId).collect(),
+            next_sound_id: sounds.len(),
+            sounds,
+            effects,
+            secs_since_start: 0.0,
+            secs_per_beat: None,
+            tempo_automation: None,
+            aux_buses: Vec::new(),
+            aux_sends: Vec::new(),
+            grain_smoothing: 0,
+            previous_tail: None,
+            history: History::default(),
+            beat_clock: None,
+        }
+    }
+
+    /// Publish the beat position at the start of the next grain to the beat clock, if there is one and the composition has a tempo.
+    /// With tempo automation, the tempo through the grain is the automated tempo at its start.
+    fn publish_beat(&self) {
+        let Some(beat_clock) = &self.beat_clock else {
+            return;
+        };
+
+        let (beats, secs_per_beat) = match &self.tempo_automation {
+            Some(tempo_automation) => (
+                tempo_automation.beats_at(self.secs_since_start),
+                60.0 / tempo_automation.bpm_at(self.secs_since_start),
+            ),
+            None => {
+                let Some(secs_per_beat) = self.secs_per_beat() else {
+                    return;
+                };
+                (self.secs_since_start / secs_per_beat, secs_per_beat)
+            },
+        };
+        beat_clock.publish(beats, 1.0 / (secs_per_beat * *SAMPLE_RATE as f32));
+    }
+
+    /// Convert a number of beats since the start to seconds since the start, following the composition's tempo.
+    /// Returns `None` if the composition has no tempo.
+    pub fn beats_to_secs(&self, beats: f32) -> Option<f32> {
+        if let Some(tempo_automation) = &self.tempo_automation {
+            return Some(tempo_automation.secs_at(beats));
+        }
+
+        self.secs_per_beat().map(|secs_per_beat| beats * secs_per_beat)
+    }
+
+    /// Add a sound, which starts playing from the next grain.
+    /// To change a composition while it plays, share it with `start_shared_sound` and lock it first.
+    pub fn add_sound(&mut self, sound: Sound) -> SoundId {
+        let id = SoundId(self.next_sound_id);
+        self.next_sound_id += 1;
+        self.sounds.push(sound);
+        self.sound_ids.push(id);
+
+        id
     }
+
+    /// Remove a sound and its aux sends, returning it if it was in the composition.
+    pub fn remove_sound(&mut self, id: SoundId) -> Option<Sound> {
+        let index = self.sound_ids.iter().position(|sound_id| *sound_id == id)?;
+        self.sound_ids.remove(index);
+        self.aux_sends.retain(|send| send.sound != id);
+
+        Some(self.sounds.remove(index))
+    }
+
+    /// Crossfade the head of the grain from a continuation of the previous grain's tail, hiding a jump at the boundary.
+    /// Grains that carry on smoothly are left alone.
+    fn smooth_grain_boundary(&mut self, grain: &mut Grain) {
+        if let Some((last, slope)) = self.previous_tail {
+            // a smooth signal steps about as far across the boundary as either side of it
+            let jump = (grain[0] - last).abs();
+            let expected = slope.abs().max((grain[1] - grain[0]).abs());
+            if jump > DISCONTINUITY_RATIO * expected {
+                let length = self.grain_smoothing.min(SAMPLES_PER_GRAIN);
+                for (i, sample) in grain.iter_mut().take(length).enumerate() {
+                    let continued = last + slope * (i + 1) as f32;
+                    let fade_in = 0.5 - 0.5 * (PI * (i + 1) as f32 / (length + 1) as f32).cos();
+                    *sample = continued * (1.0 - fade_in) + *sample * fade_in;
+                }
+            }
+        }
+
+        let last = grain[SAMPLES_PER_GRAIN - 1];
+        self.previous_tail = Some((last, last - grain[SAMPLES_PER_GRAIN - 2]));
+    }
+
+    /// Insert an effect at `index` in the composition's own effect chain, which runs after its sounds are mixed.
+    /// Later effects shift back. Panics if `index` is past the end.
+    pub fn insert_effect(&mut self, index: usize, effect: Effect) {
+        self.effects.insert(index, effect);
+    }
+
+    /// Remove and return the effect at `index`, or `None` if there is none.
+    pub fn remove_effect(&mut self, index: usize) -> Option<Effect> {
+        (index < self.effects.len()).then(|| self.effects.remove(index))
+    }
+
+    pub fn effect_count(&self) -> usize {
+        self.effects.len()
+    }
+
+    /// How many samples late the composition's output is when played, from effects that delay their signal and the
+    /// grain the player buffers. The device's own buffer, `PlaybackHandle::buffer_size` frames, comes on top of this.
+    pub fn total_latency(&self) -> usize {
+        self.effects_latency() + SAMPLES_PER_GRAIN
+    }
+
+    /// The latency from effects alone. Sounds mixed in parallel are not aligned, so this is the latency of the slowest path.
+    pub(crate) fn effects_latency(&self) -> usize {
+        let sounds_latency = self.sounds.iter().map(Sound::latency_samples).max().unwrap_or(0);
+        let buses_latency = self.aux_buses.iter().map(|bus| bus.latency_samples()).max().unwrap_or(0);
+        let sends_latency = if self.aux_sends.is_empty() { 0 } else { sounds_latency + buses_latency };
+
+        sounds_latency.max(sends_latency) + chain_latency(&self.effects)
+    }
+
+    /// How many sounds are currently playing, counting through nested compositions, without advancing anything.
+    /// Oscillators count while pressed or releasing and samples while triggered. Live input always counts.
+    /// Effect tails (e.g. delay echoes) after a sound stops are not counted.
+    pub fn active_voice_count(&self) -> usize {
+        self.sounds.iter().map(Sound::active_voice_count).sum()
+    }
+
+    /// Check every sound and effect for settings that would panic or misbehave during playback,
+    /// so mistakes are caught before the composition is handed to the audio thread.
+    pub fn validate(&self) -> Result<(), Vec<CompositionError>> {
+        let mut errors = Vec::new();
+        self.collect_errors(&mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn collect_errors(&self, errors: &mut Vec<CompositionError>) {
+        if let Some(secs_per_beat) = self.secs_per_beat.filter(|secs_per_beat| !(secs_per_beat.is_finite() && *secs_per_beat > 0.0)) {
+            errors.push(CompositionError::InvalidTempo(secs_per_beat));
+        }
+
+        for sound in &self.sounds {
+            sound.collect_errors(errors);
+        }
+        for effect in self.effects.iter().chain(&self.aux_buses) {
+            effect.collect_errors(self.secs_per_beat(), errors);
+        }
+    }
+}
+
+/// Convert a number of beats to seconds at the given tempo in beats per minute.
+pub fn beats_to_secs(bpm: f32, beats: f32) -> f32 {
+    beats * 60.0 / bpm
 }
 
 impl SoundTrait for Composition {
+    /// The tempo set on the composition, or the tempo shared by all of its sounds if none was set.
     fn secs_per_beat(&self) -> Option<f32> {
-        None
+        if let Some(tempo_automation) = &self.tempo_automation {
+            return Some(60.0 / tempo_automation.bpm_at(self.secs_since_start));
+        }
+
+        if self.secs_per_beat.is_some() {
+            return self.secs_per_beat;
+        }
+
+        let mut tempos = self.sounds.iter().map(|sound| sound.secs_per_beat());
+        let first = tempos.next()??;
+        if tempos.all(|tempo| tempo == Some(first)) {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    /// The length of the longest sound, 0 if there are none, or `None` if any sound never ends.
+    fn length_secs(&self) -> Option<f32> {
+        let mut longest = 0.0f32;
+        for sound in &self.sounds {
+            longest = longest.max(sound.length_secs()?);
+        }
+
+        Some(longest)
     }
 
     fn add_effect(&mut self, effect: Effect) {
@@ -46,8 +341,18 @@ impl SoundTrait for Composition {
         Box::new(Self {
             // sounds: self.sounds.iter().map(|s| s.clone_box()).collect(),
             sounds: self.sounds.clone(),
+            sound_ids: self.sound_ids.clone(),
+            next_sound_id: self.next_sound_id,
             effects: self.effects.clone(),
             secs_since_start: self.secs_since_start,
+            secs_per_beat: self.secs_per_beat,
+            tempo_automation: self.tempo_automation.clone(),
+            aux_buses: self.aux_buses.clone(),
+            aux_sends: self.aux_sends.clone(),
+            grain_smoothing: self.grain_smoothing,
+            previous_tail: self.previous_tail,
+            history: self.history.clone(),
+            beat_clock: self.beat_clock.clone(),
         })
     }
 
@@ -62,23 +367,51 @@ impl SoundTrait for Composition {
     }
 
     fn next_grain(&mut self) -> Grain {
+        self.publish_beat();
+
         let mut grain = [0.0; SAMPLES_PER_GRAIN];
-        for sound in &mut self.sounds {
-            let sound_grain = sound.next_grain();
+        let mut bus_grains = vec![[0.0; SAMPLES_PER_GRAIN]; self.aux_buses.len()];
+        for (i, (sound, sound_id)) in self.sounds.iter_mut().zip(&self.sound_ids).enumerate() {
+            let sound_grain = profiling::indexed("sounds", i, || sound.next_grain());
             for (i, sample) in sound_grain.iter().enumerate() {
                 grain[i] += sample;
             }
+
+            for send in self.aux_sends.iter().filter(|send| send.sound == *sound_id) {
+                let bus_grain = &mut bus_grains[send.bus.0];
+                for (i, sample) in sound_grain.iter().enumerate() {
+                    bus_grain[i] += send.amount * sample;
+                }
+            }
+        }
+
+        let dry = grain;
+        let context = EffectContext {
+            sample_rate: *SAMPLE_RATE,
+            secs_per_beat: self.secs_per_beat(),
+            time_since_start: self.secs_since_start,
+            history: &self.history,
+        };
+
+        // process each bus once and return it to the mix
+        for (i, (bus, bus_grain)) in self.aux_buses.iter_mut().zip(bus_grains).enumerate() {
+            let output = profiling::indexed("aux_buses", i, || bus.apply(bus_grain, &context));
+            for (i, sample) in output.grain.iter().enumerate() {
+                grain[i] += sample;
+            }
         }
 
-        for effect in &mut self.effects {
-            let input = EffectInput {
-                grain,
-                time_since_start_of_beat: self.secs_since_start,
-            };
-            let output = effect.apply(input);
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            let output = profiling::indexed("effects", i, || effect.apply(grain, &context));
             grain = output.grain;
         }
 
+        self.history.push_grain(&dry);
+
+        if self.grain_smoothing > 0 {
+            self.smooth_grain_boundary(&mut grain);
+        }
+
         self.secs_since_start += SAMPLES_PER_GRAIN as f32 / *SAMPLE_RATE as f32;
 
         grain
@@ -88,11 +421,80 @@ impl SoundTrait for Composition {
 pub struct CompositionBuilder {
     sounds: Vec<Sound>,
     effects: Vec<Effect>,
+    secs_per_beat: Option<f32>,
+    tempo_automation: Option<TempoAutomation>,
+    aux_buses: Vec<Effect>,
+    aux_sends: Vec<AuxSend>,
+    grain_smoothing: usize,
+    history_samples: usize,
+    beat_clock: Option<BeatClock>,
 }
 
 impl CompositionBuilder {
     pub fn new() -> Self {
-        Self { sounds: Vec::new(), effects: Vec::new() }
+        Self {
+            sounds: Vec::new(),
+            effects: Vec::new(),
+            secs_per_beat: None,
+            tempo_automation: None,
+            aux_buses: Vec::new(),
+            aux_sends: Vec::new(),
+            grain_smoothing: 0,
+            history_samples: HISTORY_SAMPLES,
+            beat_clock: None,
+        }
+    }
+
+    /// Publish the composition's beat position to `clock` as it plays, for numbers made with `Number::sine_synced_retrig`.
+    /// Nothing is published unless the composition has a tempo.
+    pub fn beat_clock(mut self, clock: BeatClock) -> Self {
+        self.beat_clock = Some(clock);
+        self
+    }
+
+    /// How many of the composition's recent dry samples to keep for its effects to look back on. See `History`.
+    pub fn history_samples(mut self, samples: usize) -> Self {
+        self.history_samples = samples;
+        self
+    }
+
+    /// Smooth over clicks at grain boundaries, e.g. from effects whose state jumps between grains,
+    /// by crossfading the first `samples` samples of each grain from where the previous grain was heading.
+    /// Only grains that jump away from the previous one are crossfaded, so continuous sound passes through untouched.
+    /// Keep this short (a few dozen samples), since the continuation is only a straight line. 0 turns it off.
+    pub fn grain_smoothing(mut self, samples: usize) -> Self {
+        self.grain_smoothing = samples;
+        self
+    }
+
+    /// Make the tempo change over time. Each point is `(time in seconds, bpm)`, and the tempo ramps linearly between them.
+    /// This takes precedence over `bpm`.
+    pub fn tempo_automation(mut self, points: Vec<(f32, f32)>) -> Self {
+        self.tempo_automation = Some(TempoAutomation::new(points));
+        self
+    }
+
+    /// Add a shared effect bus that sounds can send to with `sound_with_send`.
+    /// The bus output is added back into the mix before the composition's own effects.
+    /// Returns the builder along with the new bus's id, e.g. `let (builder, reverb) = builder.aux_bus(effect);`.
+    pub fn aux_bus(mut self, effect: Effect) -> (Self, BusId) {
+        self.aux_buses.push(effect);
+        let bus = BusId(self.aux_buses.len() - 1);
+
+        (self, bus)
+    }
+
+    /// Add a sound, and send `amount` of its signal to the given aux bus.
+    pub fn sound_with_send(mut self, sound: Sound, bus: BusId, amount: f32) -> Self {
+        self.aux_sends.push(AuxSend { sound: SoundId(self.sounds.len()), bus, amount });
+        self.sounds.push(sound);
+        self
+    }
+
+    /// Set the tempo of the composition in beats per minute.
+    pub fn bpm(mut self, bpm: f32) -> Self {
+        self.secs_per_beat = Some(60.0 / bpm);
+        self
     }
 
     pub fn sound(mut self, sound: Sound) -> Self {
@@ -106,7 +508,238 @@ impl CompositionBuilder {
     }
 
     pub fn build(self) -> Composition {
-        Composition::new(self.sounds, self.effects)
+        let mut composition = Composition::new(self.sounds, self.effects);
+        composition.secs_per_beat = self.secs_per_beat;
+        composition.tempo_automation = self.tempo_automation;
+        composition.aux_buses = self.aux_buses;
+        composition.aux_sends = self.aux_sends;
+        composition.grain_smoothing = self.grain_smoothing;
+        composition.history = History::new(self.history_samples);
+        composition.beat_clock = self.beat_clock;
+
+        composition
+    }
+}
+
+/// Multiplies every sample of a sound by a gain, for fades and tremolo over a whole sub-mix.
+#[derive(Clone, Debug)]
+pub struct GainEnvelope {
+    sound: Box<Sound>,
+    gain: Number,
+}
+
+impl GainEnvelope {
+    pub fn new(sound: Sound, gain: Number) -> Self {
+        Self { sound: Box::new(sound), gain }
+    }
+}
+
+impl SoundTrait for GainEnvelope {
+    fn next_sample(&mut self) -> f32 {
+        self.sound.next_sample() * self.gain.next_value()
+    }
+
+    fn next_grain(&mut self) -> Grain {
+        let mut grain = self.sound.next_grain();
+        for sample in &mut grain {
+            *sample *= self.gain.next_value();
+        }
+
+        grain
+    }
+
+    fn add_effect(&mut self, effect: Effect) {
+        self.sound.add_effect(effect);
+    }
+
+    fn update_sample_rate(&mut self, sample_rate: usize) {
+        self.sound.update_sample_rate(sample_rate);
+    }
+
+    fn clone_box(&self) -> Box<dyn SoundTrait> {
+        Box::new(self.clone())
+    }
+
+    fn secs_per_beat(&self) -> Option<f32> {
+        self.sound.secs_per_beat()
+    }
+
+    fn length_secs(&self) -> Option<f32> {
+        self.sound.length_secs()
+    }
+}
+
+/// Plays a sound for a fixed duration and then goes silent, e.g. to gate noise into a one-shot.
+#[derive(Clone, Debug)]
+pub struct Timed {
+    sound: Box<Sound>,
+    duration_secs: f32,
+    elapsed_samples: usize,
+}
+
+impl Timed {
+    pub fn new(sound: Sound, duration_secs: f32) -> Self {
+        Self { sound: Box::new(sound), duration_secs, elapsed_samples: 0 }
+    }
+
+    fn duration_samples(&self) -> usize {
+        (self.duration_secs * *SAMPLE_RATE as f32) as usize
+    }
+}
+
+impl SoundTrait for Timed {
+    fn next_sample(&mut self) -> f32 {
+        if self.elapsed_samples >= self.duration_samples() {
+            return 0.0;
+        }
+        self.elapsed_samples += 1;
+
+        self.sound.next_sample()
+    }
+
+    fn next_grain(&mut self) -> Grain {
+        let remaining = self.duration_samples().saturating_sub(self.elapsed_samples);
+        if remaining == 0 {
+            return [0.0; SAMPLES_PER_GRAIN];
+        }
+
+        let mut grain = self.sound.next_grain();
+        for sample in grain.iter_mut().skip(remaining) {
+            *sample = 0.0;
+        }
+        self.elapsed_samples += SAMPLES_PER_GRAIN.min(remaining);
+
+        grain
+    }
+
+    fn add_effect(&mut self, effect: Effect) {
+        self.sound.add_effect(effect);
+    }
+
+    fn update_sample_rate(&mut self, sample_rate: usize) {
+        self.sound.update_sample_rate(sample_rate);
+    }
+
+    fn clone_box(&self) -> Box<dyn SoundTrait> {
+        Box::new(self.clone())
+    }
+
+    fn secs_per_beat(&self) -> Option<f32> {
+        self.sound.secs_per_beat()
+    }
+
+    /// The duration, or the sound's own length if it ends sooner.
+    fn length_secs(&self) -> Option<f32> {
+        Some(self.sound.length_secs().map_or(self.duration_secs, |length| length.min(self.duration_secs)))
+    }
+}
+
+/// Runs one sound through several independent effect chains and sums the results.
+/// The source is only pulled once per grain, so every chain hears exactly the same signal.
+#[derive(Clone, Debug)]
+pub struct Parallel {
+    sound: Box<Sound>,
+    chains: Vec<Vec<Effect>>,
+    effects: Vec<Effect>,
+    secs_since_start: f32,
+    history: History,
+}
+
+impl Parallel {
+    pub fn new(sound: Sound, chains: Vec<Vec<Effect>>) -> Self {
+        Self { sound: Box::new(sound), chains, effects: Vec::new(), secs_since_start: 0.0, history: History::default() }
+    }
+}
+
+impl SoundTrait for Parallel {
+    /// The source's next sample, dry. Effects only run on whole grains, so like other sounds' `next_sample`, this skips them.
+    fn next_sample(&mut self) -> f32 {
+        self.sound.next_sample()
+    }
+
+    fn next_grain(&mut self) -> Grain {
+        let source = self.sound.next_grain();
+        self.secs_since_start += SAMPLES_PER_GRAIN as f32 / *SAMPLE_RATE as f32;
+        let context = EffectContext {
+            sample_rate: *SAMPLE_RATE,
+            secs_per_beat: self.secs_per_beat(),
+            time_since_start: self.secs_since_start,
+            history: &self.history,
+        };
+
+        let mut grain = [0.0; SAMPLES_PER_GRAIN];
+        for (c, chain) in self.chains.iter_mut().enumerate() {
+            let mut chain_grain = source;
+            for (i, effect) in chain.iter_mut().enumerate() {
+                chain_grain = profiling::indexed("chains", c, || profiling::indexed("effects", i, || effect.apply(chain_grain, &context))).grain;
+            }
+
+            for (i, sample) in chain_grain.iter().enumerate() {
+                grain[i] += sample;
+            }
+        }
+
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            grain = profiling::indexed("effects", i, || effect.apply(grain, &context)).grain;
+        }
+        self.history.push_grain(&source);
+
+        grain
+    }
+
+    /// Adds an effect after the chains are summed.
+    fn add_effect(&mut self, effect: Effect) {
+        self.effects.push(effect);
+    }
+
+    fn update_sample_rate(&mut self, sample_rate: usize) {
+        self.sound.update_sample_rate(sample_rate);
+    }
+
+    fn clone_box(&self) -> Box<dyn SoundTrait> {
+        Box::new(self.clone())
+    }
+
+    fn secs_per_beat(&self) -> Option<f32> {
+        self.sound.secs_per_beat()
+    }
+
+    fn length_secs(&self) -> Option<f32> {
+        self.sound.length_secs()
+    }
+}
+
+pub struct ParallelBuilder {
+    sound: Option<Sound>,
+    chains: Vec<Vec<Effect>>,
+}
+
+impl ParallelBuilder {
+    pub fn new() -> Self {
+        Self { sound: None, chains: Vec::new() }
+    }
+
+    pub fn sound(mut self, sound: Sound) -> Self {
+        self.sound = Some(sound);
+        self
+    }
+
+    /// Add an effect chain. An empty chain passes the dry signal through.
+    pub fn chain(mut self, effects: Vec<Effect>) -> Self {
+        self.chains.push(effects);
+        self
+    }
+
+    /// # Panics
+    /// If no sound was given.
+    pub fn build(self) -> Parallel {
+        Parallel::new(self.sound.unwrap(), self.chains)
+    }
+}
+
+impl Default for ParallelBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -115,6 +748,11 @@ pub enum Sound {
     Oscillator(Oscillator),
     Sample(Sample),
     Composition(Composition),
+    GainEnvelope(GainEnvelope),
+    Parallel(Parallel),
+    Input(InputSound),
+    Timed(Timed),
+    Metronome(Metronome),
 }
 
 impl Sound {
@@ -123,8 +761,83 @@ impl Sound {
             Sound::Oscillator(oscillator) => oscillator.add_effect(effect),
             Sound::Sample(sample) => sample.add_effect(effect),
             Sound::Composition(composition) => composition.add_effect(effect),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.add_effect(effect),
+            Sound::Parallel(parallel) => parallel.add_effect(effect),
+            Sound::Input(input) => input.add_effect(effect),
+            Sound::Metronome(metronome) => metronome.add_effect(effect),
+            Sound::Timed(timed) => timed.add_effect(effect),
+        }
+    }
+
+    /// How many samples late the sound's output is. See `Composition::total_latency`.
+    pub fn latency_samples(&self) -> usize {
+        match self {
+            Sound::Oscillator(oscillator) => oscillator.latency_samples(),
+            Sound::Sample(sample) => sample.latency_samples(),
+            Sound::Composition(composition) => composition.effects_latency(),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.sound.latency_samples(),
+            Sound::Parallel(parallel) => {
+                let chains_latency = parallel.chains.iter().map(|chain| chain_latency(chain)).max().unwrap_or(0);
+
+                parallel.sound.latency_samples() + chains_latency + chain_latency(&parallel.effects)
+            },
+            Sound::Input(input) => input.latency_samples(),
+            Sound::Metronome(metronome) => metronome.latency_samples(),
+            Sound::Timed(timed) => timed.sound.latency_samples(),
+        }
+    }
+
+    fn active_voice_count(&self) -> usize {
+        match self {
+            Sound::Oscillator(oscillator) => usize::from(!matches!(oscillator.state, OscillatorState::Idle)),
+            Sound::Sample(sample) => usize::from(sample.is_playing()),
+            Sound::Composition(composition) => composition.active_voice_count(),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.sound.active_voice_count(),
+            Sound::Parallel(parallel) => parallel.sound.active_voice_count(),
+            Sound::Input(_) => 1,
+            Sound::Metronome(_) => 1,
+            Sound::Timed(timed) => {
+                if timed.elapsed_samples < timed.duration_samples() {
+                    timed.sound.active_voice_count()
+                } else {
+                    0
+                }
+            },
+        }
+    }
+
+    fn collect_errors(&self, errors: &mut Vec<CompositionError>) {
+        match self {
+            Sound::Oscillator(oscillator) => oscillator.collect_errors(errors),
+            Sound::Sample(sample) => sample.collect_errors(errors),
+            Sound::Composition(composition) => composition.collect_errors(errors),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.sound.collect_errors(errors),
+            Sound::Parallel(parallel) => {
+                parallel.sound.collect_errors(errors);
+                for effect in parallel.chains.iter().flatten().chain(&parallel.effects) {
+                    effect.collect_errors(parallel.secs_per_beat(), errors);
+                }
+            },
+            Sound::Input(input) => input.collect_errors(errors),
+            Sound::Metronome(metronome) => metronome.collect_errors(errors),
+            Sound::Timed(timed) => timed.sound.collect_errors(errors),
         }
     }
+
+    /// Wrap the sound so every sample is multiplied by `gain`.
+    pub fn gain_automation(self, gain: Number) -> Self {
+        Sound::GainEnvelope(GainEnvelope::new(self, gain))
+    }
+
+    /// Wrap the sound so it goes silent after `duration_secs`.
+    pub fn timed(self, duration_secs: f32) -> Self {
+        Sound::Timed(Timed::new(self, duration_secs))
+    }
+
+    /// A click track at `bpm`, accenting every `accent_every` beats. Use `Metronome` directly to change the click pitch or length.
+    pub fn metronome(bpm: f32, accent_every: u8) -> Self {
+        Sound::Metronome(Metronome::new(bpm, accent_every))
+    }
 }
 
 impl SoundTrait for Sound {
@@ -137,14 +850,24 @@ impl SoundTrait for Sound {
             Sound::Oscillator(oscillator) => oscillator.next_sample(),
             Sound::Sample(sample) => sample.next_sample(),
             Sound::Composition(composition) => composition.next_sample(),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.next_sample(),
+            Sound::Parallel(parallel) => parallel.next_sample(),
+            Sound::Input(input) => input.next_sample(),
+            Sound::Metronome(metronome) => metronome.next_sample(),
+            Sound::Timed(timed) => timed.next_sample(),
         }
     }
 
     fn next_grain(&mut self) -> Grain {
         match self {
-            Sound::Oscillator(oscillator) => oscillator.next_grain(),
-            Sound::Sample(sample) => sample.next_grain(),
-            Sound::Composition(composition) => composition.next_grain(),
+            Sound::Oscillator(oscillator) => profiling::time("Sound::Oscillator", || oscillator.next_grain()),
+            Sound::Sample(sample) => profiling::time("Sound::Sample", || sample.next_grain()),
+            Sound::Composition(composition) => profiling::time("Sound::Composition", || composition.next_grain()),
+            Sound::GainEnvelope(gain_envelope) => profiling::time("Sound::GainEnvelope", || gain_envelope.next_grain()),
+            Sound::Parallel(parallel) => profiling::time("Sound::Parallel", || parallel.next_grain()),
+            Sound::Input(input) => profiling::time("Sound::Input", || input.next_grain()),
+            Sound::Metronome(metronome) => profiling::time("Sound::Metronome", || metronome.next_grain()),
+            Sound::Timed(timed) => profiling::time("Sound::Timed", || timed.next_grain()),
         }
     }
 
@@ -153,6 +876,24 @@ impl SoundTrait for Sound {
             Sound::Oscillator(oscillator) => oscillator.secs_per_beat(),
             Sound::Sample(sample) => sample.secs_per_beat(),
             Sound::Composition(composition) => composition.secs_per_beat(),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.secs_per_beat(),
+            Sound::Parallel(parallel) => parallel.secs_per_beat(),
+            Sound::Input(input) => input.secs_per_beat(),
+            Sound::Metronome(metronome) => metronome.secs_per_beat(),
+            Sound::Timed(timed) => timed.secs_per_beat(),
+        }
+    }
+
+    fn length_secs(&self) -> Option<f32> {
+        match self {
+            Sound::Oscillator(oscillator) => oscillator.length_secs(),
+            Sound::Sample(sample) => sample.length_secs(),
+            Sound::Composition(composition) => composition.length_secs(),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.length_secs(),
+            Sound::Parallel(parallel) => parallel.length_secs(),
+            Sound::Input(input) => input.length_secs(),
+            Sound::Metronome(metronome) => metronome.length_secs(),
+            Sound::Timed(timed) => timed.length_secs(),
         }
     }
 
@@ -161,6 +902,11 @@ impl SoundTrait for Sound {
             Sound::Oscillator(oscillator) => oscillator.add_effect(effect),
             Sound::Sample(sample) => sample.add_effect(effect),
             Sound::Composition(composition) => composition.add_effect(effect),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.add_effect(effect),
+            Sound::Parallel(parallel) => parallel.add_effect(effect),
+            Sound::Input(input) => input.add_effect(effect),
+            Sound::Metronome(metronome) => metronome.add_effect(effect),
+            Sound::Timed(timed) => timed.add_effect(effect),
         }
     }
 
@@ -169,6 +915,117 @@ impl SoundTrait for Sound {
             Sound::Oscillator(oscillator) => oscillator.update_sample_rate(sample_rate),
             Sound::Sample(sample) => sample.update_sample_rate(sample_rate),
             Sound::Composition(composition) => composition.update_sample_rate(sample_rate),
+            Sound::GainEnvelope(gain_envelope) => gain_envelope.update_sample_rate(sample_rate),
+            Sound::Parallel(parallel) => parallel.update_sample_rate(sample_rate),
+            Sound::Input(input) => input.update_sample_rate(sample_rate),
+            Sound::Metronome(metronome) => metronome.update_sample_rate(sample_rate),
+            Sound::Timed(timed) => timed.update_sample_rate(sample_rate),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{effects::{Duration, ReverseBuffer, Volume}, oscillator::Retrigger};
+
+    #[test]
+    fn total_latency_includes_delaying_effects() {
+        crate::player::fix_sample_rate(48000);
+        let reverse = || Effect::ReverseBuffer(ReverseBuffer::new(Duration::Secs(0.25), Number::number(1.0)));
+        let inner = CompositionBuilder::new().effect(reverse()).build();
+        let composition = CompositionBuilder::new()
+            .sound(Sound::Composition(inner))
+            .effect(reverse())
+            .build();
+
+        // two quarter-second windows, plus the one grain the player buffers
+        assert_eq!(composition.total_latency(), 2 * 12000 + SAMPLES_PER_GRAIN);
+        assert_eq!(CompositionBuilder::new().build().total_latency(), SAMPLES_PER_GRAIN);
+    }
+
+    #[test]
+    fn grain_smoothing_leaves_continuous_sound_alone() {
+        crate::player::fix_sample_rate(48000);
+        let mut composition = CompositionBuilder::new().grain_smoothing(32).build();
+        let sine = |i: usize| (2.0 * PI * 440.0 * i as f32 / 48000.0).sin();
+
+        for start in (0..8 * SAMPLES_PER_GRAIN).step_by(SAMPLES_PER_GRAIN) {
+            let grain: Grain = std::array::from_fn(|i| sine(start + i));
+            let mut smoothed = grain;
+            composition.smooth_grain_boundary(&mut smoothed);
+            assert_eq!(smoothed, grain);
+        }
+    }
+
+    #[test]
+    fn grain_smoothing_softens_jumps() {
+        crate::player::fix_sample_rate(48000);
+        let mut composition = CompositionBuilder::new().grain_smoothing(32).build();
+        composition.smooth_grain_boundary(&mut [0.0; SAMPLES_PER_GRAIN]);
+
+        let mut grain = [1.0; SAMPLES_PER_GRAIN];
+        composition.smooth_grain_boundary(&mut grain);
+        assert!(grain[0] < 0.1);
+        assert!(grain.windows(2).all(|pair| (pair[1] - pair[0]).abs() < 0.1));
+    }
+
+    #[test]
+    fn empty_composition_is_zero_secs_long() {
+        crate::player::fix_sample_rate(48000);
+        assert_eq!(CompositionBuilder::new().build().length_secs(), Some(0.0));
+    }
+
+    #[test]
+    fn parallel_sums_its_chains() {
+        crate::player::fix_sample_rate(48000);
+        let mut source = Metronome::new(120.0, 4);
+        let mut parallel = ParallelBuilder::new()
+            .sound(Sound::Metronome(source.clone()))
+            .chain(Vec::new())
+            .chain(Vec::new())
+            .build();
+
+        let dry = source.next_grain();
+        let grain = parallel.next_grain();
+        assert!(dry.iter().zip(grain).all(|(dry, wet)| wet == 2.0 * dry));
+        assert_eq!(parallel.next_sample(), source.next_sample());
+    }
+
+    #[test]
+    fn beat_clock_follows_tempo_automation() {
+        crate::player::fix_sample_rate(48000);
+        let clock = BeatClock::new();
+        let mut composition = CompositionBuilder::new()
+            .bpm(60.0)
+            .tempo_automation(vec![(0.0, 120.0)])
+            .beat_clock(clock.clone())
+            .build();
+        let mut retrigger = Retrigger::new(clock, 0.25);
+
+        // just under a second: at 120 bpm that is almost 2 beats, so 8 quarter-beat divisions start
+        let mut divisions = 0;
+        for _ in 0..93 {
+            composition.next_grain();
+            divisions += (0..SAMPLES_PER_GRAIN).filter(|_| retrigger.advance()).count();
+        }
+        assert_eq!(divisions, 8);
+    }
+
+    #[test]
+    fn sounds_can_send_to_an_aux_bus_from_a_builder_chain() {
+        crate::player::fix_sample_rate(48000);
+        let sine = || Sound::Oscillator(Oscillator::from_spec("sine A4").unwrap());
+        let mut dry = CompositionBuilder::new().sound(sine()).build();
+        let (builder, bus) = CompositionBuilder::new().aux_bus(Effect::Volume(Volume(Number::number(1.0))));
+        let mut sent = builder.sound_with_send(sine(), bus, 1.0).build();
+
+        for _ in 0..4 {
+            let dry_grain = dry.next_grain();
+            let sent_grain = sent.next_grain();
+            for (dry, sent) in dry_grain.iter().zip(sent_grain) {
+                assert!((sent - 2.0 * dry).abs() < 1e-5, "{sent} is not twice {dry}");
+            }
         }
     }
 }
@@ -0,0 +1,254 @@
+use crate::{effects::{chain_latency, Effect, EffectTrait, History}, player::{HOST, SAMPLE_RATE}, profiling, sound::{CompositionError, EffectContext, Grain, SoundTrait, SAMPLES_PER_GRAIN}};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Device, FromSample, SizedSample, Stream, StreamConfig};
+use std::{collections::VecDeque, sync::{mpsc, Arc, Mutex}};
+
+/// How many grains of input may be buffered before the oldest are dropped.
+const MAX_BUFFERED_GRAINS: f32 = 4.0;
+/// How many grains of input are kept when the buffer is trimmed.
+const TARGET_BUFFERED_GRAINS: f32 = 2.0;
+
+/// Audio captured from an input device, shared between the input callback and the sound.
+#[derive(Debug)]
+struct Capture {
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: usize,
+    _stop: mpsc::Sender<()>, // dropping this stops the input stream
+}
+
+/// Live audio from the default input device, e.g. a microphone or guitar, so it can be run through effects.
+///
+/// The input is mixed down to mono and resampled to the output sample rate. A few grains are buffered
+/// to absorb differences in buffer size between the input and output. If the input falls behind, silence is played
+/// until a grain's worth has arrived again; if it runs ahead, the oldest input is dropped to keep latency low.
+/// Clones share the same capture, so only one of them should be played.
+#[derive(Clone, Debug)]
+pub struct InputSound {
+    capture: Arc<Capture>,
+    effects: Vec<Effect>,
+    secs_since_start: f32,
+    output_sample_rate: usize,
+    primed: bool, // whether enough input has arrived to start reading
+    previous: f32,
+    current: f32,
+    fraction: f32, // how far playback is between `previous` and `current`
+    history: History,
+}
+
+impl InputSound {
+    /// Start capturing from the default input device. Panics if there is no input device or it can't be opened.
+    pub fn from_default_device() -> Self {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+
+        // streams can't always be sent between threads, so the stream lives on its own thread until stopped
+        std::thread::spawn(move || {
+            let device = HOST.default_input_device().unwrap();
+            let default_config = device.default_input_config().unwrap();
+            let sample_rate = default_config.sample_rate().0 as usize;
+            let config: StreamConfig = default_config.clone().into();
+            let buffer = Arc::new(Mutex::new(VecDeque::new()));
+
+            let stream = match default_config.sample_format() {
+                cpal::SampleFormat::F32 => build_input_stream::<f32>(&device, &config, sample_rate, buffer.clone()),
+                cpal::SampleFormat::I16 => build_input_stream::<i16>(&device, &config, sample_rate, buffer.clone()),
+                cpal::SampleFormat::U16 => build_input_stream::<u16>(&device, &config, sample_rate, buffer.clone()),
+                _ => panic!("Unsupported sample format"),
+            }.unwrap();
+            stream.play().unwrap();
+
+            ready_sender.send((buffer, sample_rate)).unwrap();
+            let _ = stop_receiver.recv(); // returns once the sender is dropped
+        });
+
+        let (buffer, sample_rate) = ready_receiver.recv().expect("failed to open the input device");
+
+        Self::with_capture(Capture { buffer, sample_rate, _stop: stop_sender })
+    }
+
+    fn with_capture(capture: Capture) -> Self {
+        Self {
+            capture: Arc::new(capture),
+            effects: Vec::new(),
+            secs_since_start: 0.0,
+            output_sample_rate: *SAMPLE_RATE,
+            primed: false,
+            previous: 0.0,
+            current: 0.0,
+            fraction: 0.0,
+            history: History::default(),
+        }
+    }
+
+    pub(crate) fn latency_samples(&self) -> usize {
+        chain_latency(&self.effects)
+    }
+
+    pub(crate) fn collect_errors(&self, errors: &mut Vec<CompositionError>) {
+        for effect in &self.effects {
+            effect.collect_errors(None, errors);
+        }
+    }
+
+    /// The next output sample, linearly interpolated between input samples.
+    fn next_resampled(&mut self, buffer: &mut VecDeque<f32>) -> f32 {
+        let step = self.capture.sample_rate as f32 / self.output_sample_rate as f32;
+        let grain_of_input = SAMPLES_PER_GRAIN as f32 * step;
+
+        // wait for a grain's worth of input before starting, or after running dry, so the next grains don't run dry too
+        if !self.primed {
+            if (buffer.len() as f32) < grain_of_input {
+                return 0.0;
+            }
+            self.primed = true;
+        }
+
+        // if the input has run ahead, skip to the most recent input rather than keeping the extra latency
+        if buffer.len() as f32 > MAX_BUFFERED_GRAINS * grain_of_input {
+            let excess = buffer.len() - (TARGET_BUFFERED_GRAINS * grain_of_input) as usize;
+            buffer.drain(..excess);
+        }
+
+        self.fraction += step;
+        while self.fraction >= 1.0 {
+            self.fraction -= 1.0;
+            self.previous = self.current;
+            self.current = match buffer.pop_front() {
+                Some(sample) => sample,
+                None => {
+                    self.primed = false;
+                    0.0
+                }
+            };
+        }
+
+        self.previous + (self.current - self.previous) * self.fraction
+    }
+}
+
+fn build_input_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    sample_rate: usize,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let max_buffered = sample_rate / 2;
+
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let mut buffer = buffer.lock().unwrap();
+            for frame in data.chunks(channels) {
+                let sum: f32 = frame.iter().map(|sample| sample.to_sample::<f32>()).sum();
+                buffer.push_back(sum / channels as f32);
+            }
+
+            // drop the oldest input rather than letting latency grow without limit
+            let excess = buffer.len().saturating_sub(max_buffered);
+            buffer.drain(..excess);
+        },
+        |err| eprintln!("Audio input stream error: {err}"),
+        None,
+    )
+}
+
+impl SoundTrait for InputSound {
+    fn next_sample(&mut self) -> f32 {
+        self.secs_since_start += 1.0 / self.output_sample_rate as f32;
+
+        let buffer = self.capture.buffer.clone();
+        let mut buffer = buffer.lock().unwrap();
+        self.next_resampled(&mut buffer)
+    }
+
+    fn next_grain(&mut self) -> Grain {
+        let mut grain = [0.0; SAMPLES_PER_GRAIN];
+        {
+            let buffer = self.capture.buffer.clone();
+            let mut buffer = buffer.lock().unwrap();
+            for sample in &mut grain {
+                *sample = self.next_resampled(&mut buffer);
+            }
+        }
+        self.secs_since_start += SAMPLES_PER_GRAIN as f32 / self.output_sample_rate as f32;
+
+        let context = EffectContext {
+            sample_rate: self.output_sample_rate,
+            secs_per_beat: None,
+            time_since_start: self.secs_since_start,
+            history: &self.history,
+        };
+        let dry = grain;
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            grain = profiling::indexed("effects", i, || effect.apply(grain, &context)).grain;
+        }
+        self.history.push_grain(&dry);
+
+        grain
+    }
+
+    fn add_effect(&mut self, effect: Effect) {
+        self.effects.push(effect);
+    }
+
+    fn update_sample_rate(&mut self, sample_rate: usize) {
+        self.output_sample_rate = sample_rate;
+    }
+
+    fn clone_box(&self) -> Box<dyn SoundTrait> {
+        Box::new(self.clone())
+    }
+
+    fn secs_per_beat(&self) -> Option<f32> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input_sound(buffer: &Arc<Mutex<VecDeque<f32>>>) -> InputSound {
+        crate::player::fix_sample_rate(48000);
+        let (stop_sender, _) = mpsc::channel();
+        let mut sound = InputSound::with_capture(Capture { buffer: buffer.clone(), sample_rate: 48000, _stop: stop_sender });
+        sound.output_sample_rate = 48000;
+
+        sound
+    }
+
+    #[test]
+    fn waits_for_a_grain_of_input_again_after_running_dry() {
+        let buffer = Arc::new(Mutex::new(VecDeque::from(vec![0.5; SAMPLES_PER_GRAIN])));
+        let mut sound = input_sound(&buffer);
+        let mut queue = buffer.lock().unwrap();
+
+        for _ in 0..=SAMPLES_PER_GRAIN {
+            sound.next_resampled(&mut queue);
+        }
+        assert!(!sound.primed);
+
+        queue.extend([0.5; 10]);
+        assert_eq!(sound.next_resampled(&mut queue), 0.0);
+        assert_eq!(queue.len(), 10);
+
+        queue.extend(vec![0.5; SAMPLES_PER_GRAIN]);
+        sound.next_resampled(&mut queue);
+        assert!(sound.primed);
+    }
+
+    #[test]
+    fn trims_a_full_buffer_back_to_the_target() {
+        let buffer = Arc::new(Mutex::new(VecDeque::from(vec![0.5; SAMPLES_PER_GRAIN * 10])));
+        let mut sound = input_sound(&buffer);
+        let mut queue = buffer.lock().unwrap();
+
+        sound.next_resampled(&mut queue);
+        assert_eq!(queue.len(), TARGET_BUFFERED_GRAINS as usize * SAMPLES_PER_GRAIN - 1);
+    }
+}
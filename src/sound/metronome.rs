@@ -0,0 +1,131 @@
+use crate::{effects::{chain_latency, Effect, EffectTrait, History}, player::SAMPLE_RATE, profiling, sound::{CompositionError, EffectContext, Grain, SoundTrait, SAMPLES_PER_GRAIN}};
+use std::f32::consts::PI;
+
+const DEFAULT_CLICK_FREQUENCY: f32 = 1000.0;
+const DEFAULT_ACCENT_FREQUENCY: f32 = 1500.0;
+const DEFAULT_CLICK_SECS: f32 = 0.03;
+
+/// A click track: a short Hann-windowed sine blip on every beat, with a higher-pitched accent on the first beat of each bar.
+/// Beats are counted in samples, so the clicks stay in time however long it plays.
+#[derive(Clone, Debug)]
+pub struct Metronome {
+    bpm: f32,
+    accent_every: u8, // beats per bar; 0 never accents
+    click_frequency: f32,
+    accent_frequency: f32,
+    click_secs: f32,
+    sample_rate: usize,
+    samples_since_start: u64,
+    effects: Vec<Effect>,
+    history: History,
+}
+
+impl Metronome {
+    pub fn new(bpm: f32, accent_every: u8) -> Self {
+        assert!(bpm > 0.0, "tempo must be positive");
+
+        Self {
+            bpm,
+            accent_every,
+            click_frequency: DEFAULT_CLICK_FREQUENCY,
+            accent_frequency: DEFAULT_ACCENT_FREQUENCY,
+            click_secs: DEFAULT_CLICK_SECS,
+            sample_rate: *SAMPLE_RATE,
+            samples_since_start: 0,
+            effects: Vec::new(),
+            history: History::default(),
+        }
+    }
+
+    /// Set the pitches of ordinary clicks and accented clicks, in Hz.
+    pub fn pitches(mut self, click_frequency: f32, accent_frequency: f32) -> Self {
+        self.click_frequency = click_frequency;
+        self.accent_frequency = accent_frequency;
+        self
+    }
+
+    /// Set how long each click lasts, in seconds. Clicks longer than a beat are cut short at the next beat.
+    pub fn click_length(mut self, secs: f32) -> Self {
+        assert!(secs > 0.0, "click length must be positive");
+        self.click_secs = secs;
+        self
+    }
+
+    pub(crate) fn latency_samples(&self) -> usize {
+        chain_latency(&self.effects)
+    }
+
+    pub(crate) fn collect_errors(&self, errors: &mut Vec<CompositionError>) {
+        for effect in &self.effects {
+            effect.collect_errors(Some(60.0 / self.bpm), errors);
+        }
+    }
+
+    fn samples_per_beat(&self) -> f64 {
+        60.0 * self.sample_rate as f64 / self.bpm as f64
+    }
+
+    fn secs_since_start(&self) -> f32 {
+        (self.samples_since_start as f64 / self.sample_rate as f64) as f32
+    }
+}
+
+impl SoundTrait for Metronome {
+    fn next_sample(&mut self) -> f32 {
+        let samples_per_beat = self.samples_per_beat();
+        let position = self.samples_since_start as f64;
+        self.samples_since_start += 1;
+
+        let beat = (position / samples_per_beat).floor();
+        let secs_into_beat = ((position - beat * samples_per_beat) / self.sample_rate as f64) as f32;
+        let click_secs = self.click_secs.min((samples_per_beat / self.sample_rate as f64) as f32);
+        if secs_into_beat >= click_secs {
+            return 0.0;
+        }
+
+        let accented = self.accent_every > 0 && (beat as u64).is_multiple_of(u64::from(self.accent_every));
+        let frequency = if accented { self.accent_frequency } else { self.click_frequency };
+        let window = (PI * secs_into_beat / click_secs).sin().powi(2);
+
+        (2.0 * PI * frequency * secs_into_beat).sin() * window
+    }
+
+    fn next_grain(&mut self) -> Grain {
+        let mut grain = [0.0; SAMPLES_PER_GRAIN];
+        for sample in &mut grain {
+            *sample = self.next_sample();
+        }
+
+        let context = EffectContext {
+            sample_rate: self.sample_rate,
+            secs_per_beat: Some(60.0 / self.bpm),
+            time_since_start: self.secs_since_start(),
+            history: &self.history,
+        };
+        let dry = grain;
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            grain = profiling::indexed("effects", i, || effect.apply(grain, &context)).grain;
+        }
+        self.history.push_grain(&dry);
+
+        grain
+    }
+
+    fn add_effect(&mut self, effect: Effect) {
+        self.effects.push(effect);
+    }
+
+    fn update_sample_rate(&mut self, sample_rate: usize) {
+        // keep the current position in seconds, so the beat doesn't jump
+        self.samples_since_start = (self.samples_since_start as f64 * sample_rate as f64 / self.sample_rate as f64) as u64;
+        self.sample_rate = sample_rate;
+    }
+
+    fn clone_box(&self) -> Box<dyn SoundTrait> {
+        Box::new(self.clone())
+    }
+
+    fn secs_per_beat(&self) -> Option<f32> {
+        Some(60.0 / self.bpm)
+    }
+}
@@ -0,0 +1,100 @@
+/// A tempo that changes over time, given as `(time in seconds, bpm)` points.
+/// The tempo ramps linearly between points, and holds its value before the first and after the last.
+///
+/// Beat positions are found by integrating the tempo: each linear segment contributes
+/// the area under its bpm curve (divided by 60) in beats. To convert beats back to seconds,
+/// whole segments are skipped until the one containing the beat is found, and then the quadratic
+/// `k/2 * t^2 + bpm * t = 60 * beats` is solved for the time `t` into that segment.
+#[derive(Clone, Debug)]
+pub struct TempoAutomation {
+    points: Vec<(f32, f32)>,
+}
+
+impl TempoAutomation {
+    pub fn new(mut points: Vec<(f32, f32)>) -> Self {
+        assert!(!points.is_empty(), "tempo automation needs at least one point");
+        assert!(points.iter().all(|(_, bpm)| *bpm > 0.0), "tempo must be positive");
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Self { points }
+    }
+
+    /// The tempo in beats per minute at the given time.
+    pub fn bpm_at(&self, secs: f32) -> f32 {
+        let (first_time, first_bpm) = self.points[0];
+        if secs <= first_time {
+            return first_bpm;
+        }
+
+        for window in self.points.windows(2) {
+            let (start_time, start_bpm) = window[0];
+            let (end_time, end_bpm) = window[1];
+            if secs < end_time {
+                let progress = (secs - start_time) / (end_time - start_time);
+                return start_bpm + (end_bpm - start_bpm) * progress;
+            }
+        }
+
+        self.points.last().unwrap().1
+    }
+
+    /// The number of beats elapsed between time 0 and the given time.
+    pub fn beats_at(&self, secs: f32) -> f32 {
+        let mut beats = 0.0;
+        let mut time = 0.0;
+
+        for (point_time, _) in &self.points {
+            if *point_time >= secs {
+                break;
+            }
+            if *point_time > time {
+                beats += self.beats_between(time, *point_time);
+                time = *point_time;
+            }
+        }
+
+        beats + self.beats_between(time, secs)
+    }
+
+    /// The time in seconds at which the given number of beats have elapsed since time 0.
+    pub fn secs_at(&self, beats: f32) -> f32 {
+        let mut remaining = beats;
+        let mut time = 0.0;
+
+        for (point_time, _) in &self.points {
+            if *point_time <= time {
+                continue;
+            }
+
+            let segment_beats = self.beats_between(time, *point_time);
+            if segment_beats >= remaining {
+                break;
+            }
+
+            remaining -= segment_beats;
+            time = *point_time;
+        }
+
+        // solve for the time into the segment starting at `time`
+        let start_bpm = self.bpm_at(time);
+        let next_point = self.points.iter().find(|(point_time, _)| *point_time > time);
+        let slope = match next_point {
+            Some((end_time, end_bpm)) => (end_bpm - start_bpm) / (end_time - time),
+            None => 0.0,
+        };
+
+        let target = 60.0 * remaining;
+        let offset = if slope.abs() < f32::EPSILON {
+            target / start_bpm
+        } else {
+            (-start_bpm + (start_bpm * start_bpm + 2.0 * slope * target).sqrt()) / slope
+        };
+
+        time + offset
+    }
+
+    /// The beats between two times that have no tempo point strictly between them.
+    fn beats_between(&self, start: f32, end: f32) -> f32 {
+        (self.bpm_at(start) + self.bpm_at(end)) / 2.0 * (end - start) / 60.0
+    }
+}